@@ -0,0 +1,206 @@
+use crate::address::canonical_ip;
+use crate::config::Config;
+use std::{fmt, net::IpAddr};
+
+/// An IP address or CIDR range, such as `192.168.1.5` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse an IP address or CIDR range.
+    pub fn parse(rule: &str) -> Option<Cidr> {
+        let (ip, prefix) = match rule.split_once('/') {
+            Some((ip, prefix)) => (ip.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (rule.parse().ok()?, None),
+        };
+        let ip = canonical_ip(ip);
+        let max_prefix = match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        match prefix.unwrap_or(max_prefix) {
+            prefix if prefix <= max_prefix => Some(Cidr {
+                network: ip,
+                prefix,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Check whether the range contains `ip`.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical_ip(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.into(), ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Check whether the first `prefix` bits of two `bits`-bit addresses are equal.
+fn prefix_matches(network: u128, ip: u128, prefix: u8, bits: u32) -> bool {
+    let shift = bits - prefix as u32;
+    shift == bits || network >> shift == ip >> shift
+}
+
+/// Rules deciding which hosts may connect to the server.
+/// Deny rules take priority, and if any allow rules are given, a host must match one of them.
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Build the access list from `allow` and `deny` rules given on the command line and in the config file.
+    pub fn new(allow: &[&str], deny: &[&str], config: &Config) -> Result<AccessList, String> {
+        let parse_all = |rules: Vec<&str>| {
+            rules
+                .iter()
+                .map(|rule| Cidr::parse(rule).ok_or(format!("invalid address or range {}", rule)))
+                .collect::<Result<Vec<Cidr>, String>>()
+        };
+
+        Ok(AccessList {
+            allow: parse_all(
+                allow
+                    .iter()
+                    .copied()
+                    .chain(config.values("", "allow"))
+                    .collect(),
+            )?,
+            deny: parse_all(
+                deny.iter()
+                    .copied()
+                    .chain(config.values("", "deny"))
+                    .collect(),
+            )?,
+        })
+    }
+
+    /// Check whether `ip` may connect, returning the reason if it may not.
+    pub fn check(&self, ip: IpAddr) -> Result<(), String> {
+        if let Some(rule) = self.deny.iter().find(|rule| rule.contains(ip)) {
+            return Err(format!("matches deny rule {}", rule));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.contains(ip)) {
+            return Err("does not match any allow rule".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        assert_eq!(
+            Cidr::parse("192.168.1.5").unwrap().to_string(),
+            "192.168.1.5/32"
+        );
+        assert_eq!(Cidr::parse("fd00::/8").unwrap().to_string(), "fd00::/8");
+        assert_eq!(
+            Cidr::parse("::ffff:10.0.0.0/8").unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+
+        assert_eq!(Cidr::parse("192.168.1.0/33"), None);
+        assert_eq!(Cidr::parse("fd00::/129"), None);
+        assert_eq!(Cidr::parse("192.168.1.0/"), None);
+        assert_eq!(Cidr::parse("192.168.1"), None);
+    }
+
+    #[test]
+    fn contains_addresses_within_the_prefix() {
+        let range = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(range.contains(ip("192.168.1.0")));
+        assert!(range.contains(ip("192.168.1.255")));
+        assert!(!range.contains(ip("192.168.2.1")));
+
+        let single = Cidr::parse("10.0.0.1").unwrap();
+        assert!(single.contains(ip("10.0.0.1")));
+        assert!(!single.contains(ip("10.0.0.2")));
+
+        let v6 = Cidr::parse("fd00::/8").unwrap();
+        assert!(v6.contains(ip("fdab::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn zero_prefix_contains_every_address_of_its_family() {
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("203.0.113.7")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+        assert!(!Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("2001:db8::1")));
+        assert!(!Cidr::parse("::/0").unwrap().contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_rules() {
+        let range = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(range.contains(ip("::ffff:192.168.1.20")));
+    }
+
+    #[test]
+    fn deny_rules_take_priority() {
+        let access =
+            AccessList::new(&["192.168.1.0/24"], &["192.168.1.13"], &Config::empty()).unwrap();
+
+        assert!(access.check(ip("192.168.1.12")).is_ok());
+        assert!(access.check(ip("192.168.1.13")).is_err());
+        assert!(access.check(ip("10.0.0.1")).is_err());
+    }
+
+    #[test]
+    fn everything_is_allowed_without_allow_rules() {
+        let access = AccessList::new(&[], &["10.0.0.0/8"], &Config::empty()).unwrap();
+
+        assert!(access.check(ip("192.168.1.12")).is_ok());
+        assert!(access.check(ip("10.1.2.3")).is_err());
+    }
+
+    #[test]
+    fn combines_rules_from_the_config_file() {
+        let config = config::parse("allow = 10.0.0.0/8\ndeny = 10.0.0.13").unwrap();
+        let access = AccessList::new(&["192.168.1.5"], &[], &config).unwrap();
+
+        assert!(access.check(ip("192.168.1.5")).is_ok());
+        assert!(access.check(ip("10.0.0.12")).is_ok());
+        assert!(access.check(ip("10.0.0.13")).is_err());
+        assert!(access.check(ip("172.16.0.1")).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(AccessList::new(&["not an address"], &[], &Config::empty()).is_err());
+        assert!(AccessList::new(&[], &["10.0.0.0/40"], &Config::empty()).is_err());
+    }
+}
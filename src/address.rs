@@ -0,0 +1,188 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket};
+
+/// The port used by the server when none is specified.
+pub const DEFAULT_PORT: u16 = 42069;
+
+/// Parse an address given on the command line, using `default_port` if it does not include one.
+///
+/// Accepts IPv4 and IPv6 addresses with or without a port (`192.168.1.5`, `192.168.1.5:42069`,
+/// `::1`, `[::1]:42069`), IPv6 addresses with a scope ID (`fe80::1%eth0`, `[fe80::1%3]:42069`),
+/// and hostnames, which are resolved.
+pub fn parse(address: &str, default_port: u16) -> Option<SocketAddr> {
+    if let Some(ip) = parse_ip(address) {
+        return Some(with_port(ip, default_port));
+    }
+
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, port) = rest.split_at(rest.find(']')?);
+        let port = match &port[1..] {
+            "" => default_port,
+            port => port.strip_prefix(':')?.parse().ok()?,
+        };
+
+        return Some(with_port(parse_ip(host)?, port));
+    }
+
+    if let Some((host, port)) = address.rsplit_once(':') {
+        if let (Some(ip), Ok(port)) = (parse_ip(host), port.parse()) {
+            return Some(with_port(ip, port));
+        }
+    }
+
+    let with_default_port = match address.contains(':') {
+        true => address.to_string(),
+        false => format!("{}:{}", address, default_port),
+    };
+
+    with_default_port.to_socket_addrs().ok()?.next()
+}
+
+/// Bind a UDP socket to the given address.
+/// Binding to the unspecified IPv6 address listens on both IPv4 and IPv6.
+pub fn bind(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+
+    if let SocketAddr::V6(v6) = address {
+        socket.set_only_v6(!v6.ip().is_unspecified())?;
+    }
+
+    socket.bind(&address.into())?;
+
+    Ok(socket.into())
+}
+
+/// The address a server listens on by default, which is dual-stack if the host supports IPv6.
+pub fn default_bind_address(port: u16) -> SocketAddr {
+    let v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+
+    match Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)) {
+        Ok(_) => v6,
+        Err(_) => SocketAddr::new(IpAddr::V4([0, 0, 0, 0].into()), port),
+    }
+}
+
+/// Convert IPv4-mapped IPv6 addresses, as reported by dual-stack sockets, back into IPv4 addresses.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// An IP address with an optional IPv6 scope ID.
+struct ScopedIp {
+    ip: IpAddr,
+    scope_id: u32,
+}
+
+/// Parse an IP address, allowing IPv6 addresses to end with `%` and a scope ID or interface name.
+fn parse_ip(address: &str) -> Option<ScopedIp> {
+    let (ip, scope) = match address.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (address, None),
+    };
+
+    match (ip.parse().ok()?, scope) {
+        (ip, None) => Some(ScopedIp { ip, scope_id: 0 }),
+        (IpAddr::V6(ip), Some(scope)) => Some(ScopedIp {
+            ip: IpAddr::V6(ip),
+            scope_id: scope_id(scope)?,
+        }),
+        (IpAddr::V4(_), Some(_)) => None,
+    }
+}
+
+/// Resolve a scope given either as a numeric ID or an interface name.
+fn scope_id(scope: &str) -> Option<u32> {
+    if let Ok(id) = scope.parse() {
+        return Some(id);
+    }
+
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(scope).ok()?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    #[cfg(not(unix))]
+    None
+}
+
+fn with_port(scoped: ScopedIp, port: u16) -> SocketAddr {
+    match scoped.ip {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V4(ip), port),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scoped.scope_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn parses_ipv4_with_and_without_port() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5));
+
+        assert_eq!(parse("192.168.1.5", 1), Some(SocketAddr::new(ip, 1)));
+        assert_eq!(
+            parse("192.168.1.5:42069", 1),
+            Some(SocketAddr::new(ip, 42069))
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_with_and_without_port() {
+        let ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+        assert_eq!(parse("::1", 1), Some(SocketAddr::new(ip, 1)));
+        assert_eq!(parse("[::1]", 1), Some(SocketAddr::new(ip, 1)));
+        assert_eq!(parse("[::1]:42069", 1), Some(SocketAddr::new(ip, 42069)));
+    }
+
+    #[test]
+    fn parses_ipv6_scope_ids() {
+        let ip: Ipv6Addr = "fe80::1".parse().unwrap();
+
+        assert_eq!(
+            parse("fe80::1%3", 1),
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, 1, 0, 3)))
+        );
+        assert_eq!(
+            parse("[fe80::1%3]:42069", 1),
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, 42069, 0, 3)))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert_eq!(parse("[::1", 1), None);
+        assert_eq!(parse("[::1]42069", 1), None);
+        assert_eq!(parse("[::1]:port", 1), None);
+        assert_eq!(parse("[192.168.1.5%3]", 1), None);
+        assert_eq!(parse("192.168.1.5%3", 1), None);
+        assert_eq!(parse("fe80::1%no-such-interface", 1), None);
+    }
+
+    #[test]
+    fn canonicalises_ipv4_mapped_addresses() {
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+
+        assert_eq!(canonical_ip(mapped), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(
+            canonical_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+}
@@ -1,181 +1,420 @@
-use crate::keyboard::pressed_keys_to_flags;
-use enigo::*;
-use minifb::{CursorStyle, Window, WindowOptions};
-use multiinput::*;
+use crate::address;
+use crate::hotkey::Hotkey;
+use crate::input::{InputEvent, InputSource};
+use crate::keyboard::{keys_to_flags, Key};
+use crate::layout::Layout;
+use crate::pairing;
 use std::{
-    convert::{TryFrom, TryInto},
-    net::UdpSocket,
-    process::exit,
+    io::{stdin, stdout, ErrorKind, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     thread::sleep,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant},
 };
 
+/// How long to wait for the server to answer a handshake message.
+/// The server ignores handshakes for a moment after rejecting one, so it may never answer.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// Represents a client and holds objects relating to it.
 pub struct Client {
-    window: Window,
-    input_manager: RawInputManager,
-    output_manager: Enigo,
+    source: Box<dyn InputSource>,
     socket: UdpSocket,
+    /// The client's keyboard layout, used to name the key flags after the letters they type.
+    layout: Layout,
+    /// The hotkey which disconnects the client and quits.
+    release: Hotkey,
+    /// The hotkey which pauses and resumes forwarding without disconnecting.
+    pause: Hotkey,
+    /// Whether the last packet failed to send, so send errors are only logged once until they stop.
+    send_failing: bool,
 }
 
-/// Initialise the client by connecting the UDP socket to the server.
-/// This also registers `multiinput` to listen for mouse events.
-pub fn init(ip: String) -> Option<Client> {
-    let mut input_manager = RawInputManager::new().unwrap();
-    input_manager.register_devices(DeviceType::Mice);
-
-    let output_manager = Enigo::new();
+/// Why the client stopped running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    /// The release hotkey was pressed.
+    Released,
+    /// The input source ran out of input.
+    Closed,
+    /// The server closed the session, such as when it was kicked.
+    Rejected,
+}
 
-    let mut window = Window::new("Remouse", 100, 100, WindowOptions::default()).unwrap();
-    window.update();
-    window.set_position(-50, -50);
-    window.set_cursor_style(CursorStyle::Arrow);
+/// Initialise the client by connecting the UDP socket to the server, capturing input from `source`.
+///
+/// The socket is bound to `source_port`, or an ephemeral port chosen by the OS if it is 0,
+/// so the client can run alongside a server or another client on the same machine.
+/// If the server requires pairing, the user is asked for the PIN it shows.
+/// Returns `Ok(None)` if the server refuses the connection.
+pub fn init(
+    server_address: SocketAddr,
+    source_port: u16,
+    source: Box<dyn InputSource>,
+    layout: Layout,
+    release: Hotkey,
+    pause: Hotkey,
+) -> std::io::Result<Option<Client>> {
+    let unspecified = match server_address {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = address::bind(SocketAddr::new(unspecified, source_port))?;
+    socket.connect(server_address)?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
 
-    let socket = UdpSocket::bind("0.0.0.0:42069").unwrap();
-    socket.connect(&ip).unwrap();
+    let client_id = pairing::load_or_create_client_id()?;
 
     let mut connect_message: [u8; 13] = [0; 13];
     connect_message[4] = 0b0100_0000;
-    socket.send(&connect_message).unwrap();
+    socket.send(&[&connect_message[..], &client_id.0].concat())?;
 
-    let mut confirm_buf: [u8; 1] = [0; 1];
-    socket.recv(&mut confirm_buf).unwrap();
+    let mut response = receive_response(&socket)?;
 
-    if confirm_buf[0] == 0xff {
-        Some(Client {
-            window,
-            input_manager,
-            output_manager,
+    // The server doesn't know this client yet, so send it the PIN it is showing
+    if response == 0x01 {
+        print!("enter the PIN shown on the server: ");
+        stdout().flush()?;
+
+        let mut pin = String::new();
+        stdin().read_line(&mut pin)?;
+        let pin: u32 = pin.trim().parse().unwrap_or(u32::MAX);
+
+        let mut pair_message: [u8; 13] = [0; 13];
+        pair_message[4] = 0b1100_0000;
+        socket.send(&[&pair_message[..], &client_id.0, &pin.to_le_bytes()].concat())?;
+        response = receive_response(&socket)?;
+    }
+
+    if response == 0xff {
+        // The server only sends anything else if it closes the session, which `run` checks for between packets
+        socket.set_nonblocking(true)?;
+
+        Ok(Some(Client {
+            source,
             socket,
-        })
+            layout,
+            release,
+            pause,
+            send_failing: false,
+        }))
     } else {
-        None
+        Ok(None)
     }
 }
 
-/// Run the client, detecting input events and sending them to the server
-pub fn run(client: &mut Client, override_movement: bool) {
-    let mut scroll: i8;
-    let mut button_flags: i8 = 0;
-    let mut last_movement_time: SystemTime = SystemTime::now();
+/// Wait for the server's one byte response to a handshake message.
+fn receive_response(socket: &UdpSocket) -> std::io::Result<u8> {
+    let mut buf: [u8; 1] = [0; 1];
+
+    match socket.recv(&mut buf) {
+        Ok(_) => Ok(buf[0]),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "the server didn't respond, it may be ignoring handshakes after rejecting one, \
+                 so try again in a moment",
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Run the client, taking input events from its source and sending them to the server.
+/// Pressing the release hotkey, or the source running out of input, disconnects and returns why.
+/// Pressing the pause hotkey stops forwarding until it is pressed again, keeping the session open.
+pub fn run(client: &mut Client) -> Stop {
+    let mut button_flags: u8 = 0;
+    let mut keys: Vec<Key> = Vec::new();
+    let mut last_movement_time = Instant::now();
+    let mut paused = false;
 
     loop {
-        let events = client.input_manager.get_events().collect::<Vec<RawEvent>>();
-
-        // Store the previous button flags to know if they changed
-        scroll = 0;
-
-        // Update the button flags to match the currently held buttons
-        // Also update `scroll` to be 1 if scrolling up, 0 if not scrolling, and -1 if scrolling down
-        events.iter().for_each(|e| match e {
-            RawEvent::MouseButtonEvent(_, button, state) => {
-                let add_or_subtract = match state {
-                    State::Pressed => 1_i8,
-                    State::Released => -1_i8,
-                };
-
-                button_flags = match button {
-                    multiinput::MouseButton::Left => button_flags + add_or_subtract,
-                    multiinput::MouseButton::Right => button_flags + 2 * add_or_subtract,
-                    multiinput::MouseButton::Middle => button_flags + 4 * add_or_subtract,
-                    _ => button_flags,
+        if rejected(client) {
+            client.source.release();
+            return Stop::Rejected;
+        }
+
+        let mut movement: Option<(i32, i32)> = None;
+        let mut scroll = 0;
+        let mut changed = false;
+
+        for event in client.source.poll() {
+            changed |= !matches!(event, InputEvent::Move(..));
+
+            match event {
+                InputEvent::Move(x, y) => {
+                    let (total_x, total_y) = movement.unwrap_or((0, 0));
+                    movement = Some((total_x + x, total_y + y));
+                }
+                InputEvent::ButtonDown(button) => button_flags |= button.flag(),
+                InputEvent::ButtonUp(button) => button_flags &= !button.flag(),
+                InputEvent::Scroll(distance) => scroll = distance.signum(),
+                InputEvent::KeyDown(key) if !keys.contains(&key) => keys.push(key),
+                InputEvent::KeyDown(_) => (),
+                InputEvent::KeyUp(key) => keys.retain(|k| *k != key),
+                InputEvent::Closed => {
+                    disconnect(client);
+                    return Stop::Closed;
                 }
             }
-            RawEvent::MouseWheelEvent(_, direction) => {
-                scroll = (*direction).clone() as i8;
-                scroll = scroll / scroll.abs();
+
+            if client.release.pressed(&event, &keys) {
+                disconnect(client);
+                return Stop::Released;
             }
-            _ => (),
-        });
-
-        button_flags &= 0b00111; // Reset scroll flags
-        button_flags = match scroll {
-            1 => button_flags | 0b0000_1000, // If scrolling up, set scroll up flag
-            -1 => button_flags | 0b0001_0000, // If scrolling down, set scroll down flag
-            _ => button_flags,               // If not scrolling, don't set scroll flags
-        };
 
-        // Update the key flags according to which keys have been pressed
-        let pressed_keys = client.window.get_keys().unwrap();
-        let key_flags_option = pressed_keys_to_flags(&pressed_keys);
+            if client.pause.pressed(&event, &keys) {
+                paused = !paused;
 
-        let key_flags: u64 = if key_flags_option == None {
-            disconnect_and_exit(client);
-            0
-        } else {
-            key_flags_option.unwrap()
-        };
+                if paused {
+                    client.source.pause();
+                    info!(event = "pause"; "paused, press the pause hotkey again to resume");
+                } else {
+                    client.source.resume();
+                    info!(event = "resume"; "resumed, streaming input");
 
-        // If the mouse has moved, send the data and set transmitted to true. Defaults to false
-        let transmitted = events
-            .iter()
-            .find_map(|e| match e {
-                RawEvent::MouseMoveEvent(_, x, y) => {
-                    last_movement_time = SystemTime::now();
-
-                    if override_movement {
-                        Some(override_movement_transmit(
-                            client,
-                            x,
-                            y,
-                            button_flags,
-                            key_flags,
-                        ))
-                    } else {
-                        Some(transmit(client, x, y, button_flags, key_flags))
-                    }
+                    // Keys held when resuming, such as the hotkey itself, aren't forwarded until pressed again
+                    keys.clear();
+                    button_flags = 0;
                 }
-                _ => None,
-            })
-            .unwrap_or(false);
-
-        // If not already sent the data this iteration, send it anyway with no movement
-        // Don't do this if the mouse has recently moved to fix issue #1
-        if !transmitted {
-            if last_movement_time.elapsed().unwrap().as_millis() > 50 {
-                transmit(client, &0, &0, button_flags, key_flags);
-                sleep(Duration::from_millis(1));
             }
         }
 
-        client.window.update();
-    }
-}
+        // While paused, keep the session alive by sending that nothing is held, and nothing else
+        let (movement, button_flags, scroll, changed, held) = match paused {
+            true => (None, 0, 0, false, &[][..]),
+            false => (movement, button_flags, scroll, changed, &keys[..]),
+        };
 
-/// Override the mouse movement by locking the mouse to the top left of the screen.
-/// This is inside the key capture box so keys are always captured.
-fn override_movement_transmit(
-    client: &mut Client,
-    x: &i32,
-    y: &i32,
-    button_flags: i8,
-    key_flags: u64,
-) -> bool {
-    client.output_manager.mouse_move_to(0, 0);
-    transmit(client, x, y, button_flags, key_flags)
+        // Set the scroll up or down flag if scrolling
+        let flags = match scroll {
+            1 => button_flags | 0b0000_1000,
+            -1 => button_flags | 0b0001_0000,
+            _ => button_flags,
+        };
+
+        // If not moving, send the state anyway with no movement
+        // Don't do this if the mouse has recently moved to fix issue #1, unless a button or key changed
+        match movement {
+            Some((x, y)) => {
+                last_movement_time = Instant::now();
+                transmit(client, x, y, flags, held);
+            }
+            None if changed || last_movement_time.elapsed() > Duration::from_millis(50) => {
+                transmit(client, 0, 0, flags, held);
+                sleep(Duration::from_millis(1));
+            }
+            None => (),
+        }
+    }
 }
 
-/// Transmits the mouse's relative movement, button states, and key presses
-fn transmit(client: &mut Client, x: &i32, y: &i32, button_flags: i8, key_flags: u64) -> bool {
-    let x_le_i16 = i16::try_from(*x).unwrap().to_le_bytes();
-    let y_le_i16 = i16::try_from(*y).unwrap().to_le_bytes();
+/// Transmits the mouse's relative movement, button states, and key presses.
+/// The keys are sent both as flags and as a list of physical keys, so older servers can still understand them.
+fn transmit(client: &mut Client, x: i32, y: i32, button_flags: u8, keys: &[Key]) {
+    let x_le_i16 = (x.clamp(i16::MIN as i32, i16::MAX as i32) as i16).to_le_bytes();
+    let y_le_i16 = (y.clamp(i16::MIN as i32, i16::MAX as i32) as i16).to_le_bytes();
+    let key_flags = keys_to_flags(keys, client.layout);
 
     let mut to_send = [x_le_i16, y_le_i16].concat();
-    to_send.push(button_flags.try_into().unwrap());
+    to_send.push(button_flags);
     to_send.extend_from_slice(&key_flags.to_le_bytes());
+    to_send.push(keys.len() as u8);
+    to_send.extend(keys.iter().map(|key| key.usage()));
 
-    client.socket.send(&to_send).unwrap();
+    match client.socket.send(&to_send) {
+        Ok(_) if client.send_failing => {
+            info!(event = "send_recovered"; "sending input again");
+            client.send_failing = false;
+        }
+        Ok(_) => (),
+        Err(e) if !client.send_failing => {
+            warn!(event = "send_error", error = e; "failed to send input: {}", e);
+            client.send_failing = true;
+        }
+        Err(_) => (),
+    }
+}
 
-    true
+/// Check whether the server has sent `0x00` to say it closed the session.
+fn rejected(client: &Client) -> bool {
+    let mut buf: [u8; 1] = [0; 1];
+    matches!(client.socket.recv(&mut buf), Ok(1) if buf[0] == 0x00)
 }
 
-/// Send a disconnect message and quit the program.
+/// Give the input back and send a disconnect message.
 /// The disconnect message is 13 bytes of zeroes, resetting every key and mouse button to an up state.
 /// The 3rd bit of the 5th byte is a 1, indicating to the server that the client wishes to disconnect.
-fn disconnect_and_exit(client: &mut Client) {
+fn disconnect(client: &mut Client) {
+    client.source.release();
+
     let mut to_send: [u8; 13] = [0; 13];
     to_send[4] = 0b_0010_0000;
 
-    client.socket.send(&to_send).unwrap();
-    exit(0);
+    if let Err(e) = client.socket.send(&to_send) {
+        warn!(event = "send_error", error = e; "failed to send disconnect message: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hotkey;
+    use crate::input::Button;
+    use std::collections::VecDeque;
+
+    /// An input source which gives out a scripted batch of events on each poll.
+    struct MockSource {
+        batches: VecDeque<Vec<InputEvent>>,
+    }
+
+    impl InputSource for MockSource {
+        fn poll(&mut self) -> Vec<InputEvent> {
+            self.batches.pop_front().unwrap_or(vec![InputEvent::Closed])
+        }
+    }
+
+    /// Create a client with the scripted input, connected to `server` as if it had accepted.
+    fn client(server: &UdpSocket, batches: Vec<Vec<InputEvent>>) -> Client {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        Client {
+            source: Box::new(MockSource {
+                batches: batches.into(),
+            }),
+            socket,
+            layout: Layout::Us,
+            release: Hotkey::parse(hotkey::DEFAULT_RELEASE).unwrap(),
+            pause: Hotkey::parse(hotkey::DEFAULT_PAUSE).unwrap(),
+            send_failing: false,
+        }
+    }
+
+    /// Run a client with the scripted input, returning why it stopped and the packets the server received.
+    fn run_script(batches: Vec<Vec<InputEvent>>) -> (Stop, Vec<Vec<u8>>) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let stop = run(&mut client(&server, batches));
+
+        let mut packets = Vec::new();
+        let mut buf = [0; 64];
+        while let Ok(length) = server.recv(&mut buf) {
+            packets.push(buf[..length].to_vec());
+        }
+
+        (stop, packets)
+    }
+
+    /// Get the held keys from an input packet.
+    fn held(packet: &[u8]) -> Vec<Key> {
+        packet
+            .get(14..)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|usage| Key::from_usage(*usage))
+            .collect()
+    }
+
+    const DISCONNECT: [u8; 13] = [0, 0, 0, 0, 0b0010_0000, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn forwards_input_and_disconnects_when_closed() {
+        let (stop, packets) = run_script(vec![
+            vec![InputEvent::Move(3, -4), InputEvent::Move(1, 0)],
+            vec![InputEvent::ButtonDown(Button::Left), InputEvent::Scroll(-2)],
+            vec![InputEvent::KeyDown(Key::A)],
+            vec![
+                InputEvent::KeyUp(Key::A),
+                InputEvent::ButtonUp(Button::Left),
+            ],
+        ]);
+
+        assert_eq!(stop, Stop::Closed);
+        assert_eq!(packets.len(), 5);
+
+        assert_eq!(&packets[0][0..5], &[4, 0, 0xfc, 0xff, 0]);
+        assert_eq!(packets[1][4], 0b0001_0001);
+        assert_eq!(packets[2][4], 0b0000_0001);
+        assert_eq!(held(&packets[2]), vec![Key::A]);
+        assert_ne!(&packets[2][5..13], &[0; 8]);
+        assert_eq!(&packets[3][4..14], &[0; 10]);
+        assert_eq!(packets[4], DISCONNECT);
+    }
+
+    #[test]
+    fn release_hotkey_disconnects() {
+        let (stop, packets) = run_script(vec![
+            vec![
+                InputEvent::KeyDown(Key::LeftCtrl),
+                InputEvent::KeyDown(Key::RightAlt),
+                InputEvent::KeyDown(Key::LeftShift),
+                InputEvent::KeyDown(Key::R),
+            ],
+            vec![InputEvent::Move(1, 1)],
+        ]);
+
+        assert_eq!(stop, Stop::Released);
+        assert_eq!(packets, vec![DISCONNECT.to_vec()]);
+    }
+
+    #[test]
+    fn stops_when_the_server_closes_the_session() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = client(&server, vec![vec![InputEvent::Move(1, 1)]]);
+        let address = client.socket.local_addr().unwrap();
+        server.send_to(&[0x00], address).unwrap();
+
+        assert_eq!(run(&mut client), Stop::Rejected);
+    }
+
+    #[test]
+    fn handshakes_give_up_when_the_server_is_silent() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        let error = receive_response(&socket).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        server
+            .send_to(&[0x01], socket.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(receive_response(&socket).unwrap(), 0x01);
+    }
+
+    #[test]
+    fn nothing_is_forwarded_while_paused() {
+        let hotkey = [Key::LeftCtrl, Key::LeftAlt, Key::LeftShift, Key::P];
+        let press: Vec<_> = hotkey.iter().map(|key| InputEvent::KeyDown(*key)).collect();
+        let release: Vec<_> = hotkey.iter().map(|key| InputEvent::KeyUp(*key)).collect();
+
+        let (stop, packets) = run_script(vec![
+            press.clone(),
+            release.clone(),
+            vec![
+                InputEvent::Move(5, 5),
+                InputEvent::Scroll(1),
+                InputEvent::KeyDown(Key::B),
+                InputEvent::ButtonDown(Button::Right),
+            ],
+            press,
+            release,
+        ]);
+
+        assert_eq!(stop, Stop::Closed);
+        let disconnect = packets.last().unwrap();
+        assert_eq!(disconnect, &DISCONNECT);
+
+        for packet in &packets[..packets.len() - 1] {
+            assert_eq!(&packet[0..14], &[0; 14]);
+        }
+    }
 }
@@ -0,0 +1,148 @@
+use std::{env, fs, path::PathBuf};
+
+/// A configuration file, made up of `key = value` lines which can be grouped under `[section]` headers.
+/// Keys may be repeated, and lines starting with `#` are comments.
+///
+/// ```
+/// allow = 192.168.1.0/24
+/// allow = fd00::/8
+/// deny = 192.168.1.13
+/// ```
+pub struct Config {
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    section: String,
+    key: String,
+    value: String,
+}
+
+/// Load and parse the configuration file at `path`.
+pub fn load(path: &str) -> Result<Config, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse(&contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Parse the contents of a configuration file.
+pub fn parse(contents: &str) -> Result<Config, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut section = String::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+        } else if let Some((key, value)) = line.split_once('=') {
+            entries.push(Entry {
+                section: section.clone(),
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            });
+        } else {
+            return Err(format!("line {}: expected `key = value`", number + 1));
+        }
+    }
+
+    Ok(Config { entries })
+}
+
+impl Config {
+    /// An empty configuration, used when no file is given.
+    pub fn empty() -> Config {
+        Config {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Every value given for `key` in `section`, in the order they appear.
+    /// The top-level section, before any header, is named `""`.
+    pub fn values<'a>(&'a self, section: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.section == section && entry.key == key)
+            .map(|entry| entry.value.as_str())
+    }
+
+    /// Every key and value in `section`, in the order they appear.
+    pub fn entries<'a>(&'a self, section: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.section == section)
+            .map(|entry| (entry.key.as_str(), entry.value.as_str()))
+    }
+
+    /// The last value given for `key` in `section`, if any.
+    pub fn value<'a>(&'a self, section: &'a str, key: &'a str) -> Option<&'a str> {
+        self.values(section, key).last()
+    }
+}
+
+/// The directory remouse keeps its state in, such as the client ID and the trust store.
+pub fn data_dir() -> PathBuf {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+
+    base.unwrap_or_else(|| PathBuf::from(".")).join("remouse")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections_repeated_keys_and_comments() {
+        let config = parse(
+            "# a comment\n\
+             allow = 192.168.1.0/24\n\
+             \n\
+             allow=fd00::/8\n\
+             policy = merged  \n\
+             [remap]\n\
+             CapsLock = Escape\n\
+             [ other ]\n\
+             policy = exclusive\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.values("", "allow").collect::<Vec<_>>(),
+            vec!["192.168.1.0/24", "fd00::/8"]
+        );
+        assert_eq!(config.value("", "policy"), Some("merged"));
+        assert_eq!(config.value("other", "policy"), Some("exclusive"));
+        assert_eq!(
+            config.entries("remap").collect::<Vec<_>>(),
+            vec![("CapsLock", "Escape")]
+        );
+        assert_eq!(config.value("", "missing"), None);
+    }
+
+    #[test]
+    fn the_last_value_wins() {
+        let config = parse("handover = 500\nhandover = 1000").unwrap();
+        assert_eq!(config.value("", "handover"), Some("1000"));
+    }
+
+    #[test]
+    fn values_may_contain_equals_signs() {
+        let config = parse("on_connect = notify-send a=b").unwrap();
+        assert_eq!(config.value("", "on_connect"), Some("notify-send a=b"));
+    }
+
+    #[test]
+    fn reports_the_line_of_invalid_entries() {
+        let error = parse("allow = 10.0.0.1\nnonsense\n").err().unwrap();
+        assert_eq!(error, "line 2: expected `key = value`");
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
+
+/// A request sent to the running server by `remouse ctl`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// List the connected clients.
+    Sessions,
+    /// Close the session with the given ID.
+    Kick(u32),
+    /// Stop injecting input, releasing everything held, until resumed.
+    Pause,
+    Resume,
+    /// Release every key and button held down on this machine.
+    Release,
+    /// Show the keys and buttons held down on this machine.
+    Held,
+    /// Allow pairing again after it was locked by too many wrong PINs.
+    UnlockPairing,
+}
+
+impl Command {
+    /// Parse a command from its words, such as `["kick", "3"]`.
+    pub fn parse(words: &[&str]) -> Result<Command, String> {
+        match words {
+            ["sessions"] => Ok(Command::Sessions),
+            ["kick", id] => id
+                .parse()
+                .map(Command::Kick)
+                .map_err(|_| format!("invalid session ID {}", id)),
+            ["pause"] => Ok(Command::Pause),
+            ["resume"] => Ok(Command::Resume),
+            ["release"] => Ok(Command::Release),
+            ["held"] => Ok(Command::Held),
+            ["unlock-pairing"] => Ok(Command::UnlockPairing),
+            [] => Err(String::from("no command given")),
+            _ => Err(format!("unknown command {}", words.join(" "))),
+        }
+    }
+}
+
+/// Where the control socket is created if no path is given, which is in the user's runtime
+/// directory if there is one.
+pub fn default_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("remouse.sock"),
+        None => crate::config::data_dir().join("control.sock"),
+    }
+}
+
+/// A Unix domain socket which `remouse ctl` connects to, which is removed when it is dropped.
+pub struct ControlSocket {
+    #[cfg(unix)]
+    listener: std::os::unix::net::UnixListener,
+    /// Receives commands from the thread reading the socket, which starts on the first `accept()`
+    /// so that it runs in the daemon rather than the process which started it.
+    #[cfg(unix)]
+    requests: Option<std::sync::mpsc::Receiver<Request>>,
+    path: PathBuf,
+}
+
+/// A command received on the control socket, which must be answered with `reply`.
+pub struct Request {
+    pub command: Result<Command, String>,
+    #[cfg(unix)]
+    stream: std::os::unix::net::UnixStream,
+}
+
+#[cfg(unix)]
+impl ControlSocket {
+    /// Create the control socket at `path`, which only the current user can connect to.
+    /// Fails if another server is already listening there.
+    pub fn bind(path: PathBuf) -> io::Result<ControlSocket> {
+        use std::os::unix::{fs::PermissionsExt, net::UnixListener, net::UnixStream};
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // A socket left behind by a server which crashed can be replaced
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("another server is using {}", path.display()),
+                ));
+            }
+            std::fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+        Ok(ControlSocket {
+            listener,
+            requests: None,
+            path,
+        })
+    }
+
+    /// Take the next command sent to the socket, if there is one waiting.
+    /// Commands are read on a separate thread, so a slow `remouse ctl` never holds up the server.
+    pub fn accept(&mut self) -> Option<Request> {
+        let listener = &self.listener;
+        let requests = self.requests.get_or_insert_with(|| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+
+            match listener.try_clone() {
+                Ok(listener) => {
+                    std::thread::spawn(move || {
+                        for stream in listener.incoming().flatten() {
+                            if let Some(request) = read_request(stream) {
+                                if sender.send(request).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!(event = "control_error", error = e; "failed to listen for control commands: {}", e)
+                }
+            }
+
+            receiver
+        });
+
+        requests.try_recv().ok()
+    }
+}
+
+/// Read the command sent on a connection to the control socket.
+#[cfg(unix)]
+fn read_request(stream: std::os::unix::net::UnixStream) -> Option<Request> {
+    use std::io::BufRead;
+
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+        .ok()?;
+
+    let mut line = String::new();
+    io::BufReader::new(&stream).read_line(&mut line).ok()?;
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+    Some(Request {
+        command: Command::parse(&words),
+        stream,
+    })
+}
+
+#[cfg(not(unix))]
+impl ControlSocket {
+    pub fn bind(_path: PathBuf) -> io::Result<ControlSocket> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the control socket is only supported on unix",
+        ))
+    }
+
+    pub fn accept(&mut self) -> Option<Request> {
+        None
+    }
+}
+
+impl Request {
+    /// Send the response to the command back to `remouse ctl`.
+    pub fn reply(self, response: &str) {
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+
+            if let Err(e) = (&self.stream).write_all(response.as_bytes()) {
+                warn!(event = "control_error", error = e; "failed to answer control command: {}", e);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = response;
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Send a command to the server listening on the control socket at `path`, returning its response.
+#[cfg(unix)]
+pub fn send(path: &Path, command: &[&str]) -> io::Result<String> {
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command.join(" "))?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(not(unix))]
+pub fn send(_path: &Path, _command: &[&str]) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the control socket is only supported on unix",
+    ))
+}
@@ -0,0 +1,114 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Detach from the terminal and keep running in the background, sending output to `log_file`
+/// or discarding it if there is none. The original process exits once this returns in the child.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let output = match log_file {
+        Some(path) => fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?,
+        None => fs::OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let input = fs::File::open("/dev/null")?;
+
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            // The parent shares the output backend with the child, so it must exit without
+            // running any destructors which could tear it down
+            0 => (),
+            _ => libc::_exit(0),
+        }
+
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        libc::dup2(input.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_file: Option<&Path>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "daemon mode is only supported on unix",
+    ))
+}
+
+/// A file containing the ID of the running process, which is removed when it is dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> io::Result<PidFile> {
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Send a status update such as `READY=1` to systemd, if the process was started by a
+/// `Type=notify` service. Does nothing otherwise.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    // Paths starting with `@` are in the abstract namespace
+    let address = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        _ => SocketAddr::from_pathname(&path),
+    };
+
+    let result = address.and_then(|address| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &address)
+    });
+
+    if let Err(e) = result {
+        warn!("failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// How often the watchdog must be notified, if systemd has enabled it for this process.
+/// This is half of the timeout systemd gives, to leave some slack.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    let timeout: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(timeout / 2))
+}
@@ -0,0 +1,229 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+/// The port servers listen on for discovery requests.
+pub const DISCOVERY_PORT: u16 = crate::address::DEFAULT_PORT + 1;
+
+/// The datagram broadcast by `remouse discover` to find servers.
+const REQUEST: &[u8] = b"remouse discover";
+
+/// The first line of a server's answer, which is followed by `key=value` lines.
+const ANSWER_HEADER: &str = "remouse server";
+
+/// How long `remouse discover` waits for servers to answer.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A server which answered a discovery request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Found {
+    /// The address to connect to, made up of the address the server listens on, or the address
+    /// the answer came from if it listens on every address, and the server's port.
+    pub address: SocketAddr,
+    pub name: String,
+    pub version: String,
+    /// Whether another client has control and the server won't accept more.
+    pub busy: bool,
+}
+
+/// Bind the socket servers receive discovery requests on, which other servers on the same machine can share.
+/// It listens on every address even if the server doesn't, as requests are broadcast and a socket bound
+/// to a single address doesn't receive broadcasts on every platform.
+pub fn bind() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).into())?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+/// Take the next discovery request waiting on `socket`, returning where it came from.
+/// Anything else sent to the socket is skipped.
+pub fn receive(socket: &UdpSocket) -> Option<SocketAddr> {
+    let mut buf = [0; 32];
+
+    loop {
+        let (length, addr) = socket.recv_from(&mut buf).ok()?;
+        if &buf[..length] == REQUEST {
+            return Some(addr);
+        }
+    }
+}
+
+/// Answer a discovery request from `addr`, describing the server listening on `port`.
+/// If the server only listens on the address `ip`, the answer tells the client to connect there,
+/// as the answer may be sent from a different address.
+pub fn answer(socket: &UdpSocket, addr: SocketAddr, ip: Option<IpAddr>, port: u16, busy: bool) {
+    let mut answer = format!(
+        "{}\nname={}\nversion={}\nport={}\nbusy={}\n",
+        ANSWER_HEADER,
+        hostname(),
+        env!("CARGO_PKG_VERSION"),
+        port,
+        busy
+    );
+    if let Some(ip) = ip {
+        answer += &format!("address={}\n", ip);
+    }
+
+    if let Err(e) = socket.send_to(answer.as_bytes(), addr) {
+        warn!(event = "discovery_error", peer = addr, error = e; "failed to answer discovery request: {}", e);
+    }
+}
+
+/// Broadcast a discovery request on the local network and collect the servers which answer.
+pub fn discover() -> io::Result<Vec<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(REQUEST, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))?;
+
+    let mut found: Vec<Found> = Vec::new();
+    let mut buf = [0; 512];
+    let start = Instant::now();
+
+    while let Some(remaining) = DISCOVERY_TIMEOUT.checked_sub(start.elapsed()) {
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+        let (length, addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(server) = parse_answer(&buf[..length], addr) {
+            if !found.contains(&server) {
+                found.push(server);
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Parse a server's answer to a discovery request, which came from `addr`.
+fn parse_answer(answer: &[u8], addr: SocketAddr) -> Option<Found> {
+    let answer = std::str::from_utf8(answer).ok()?;
+    let mut lines = answer.lines();
+
+    if lines.next()? != ANSWER_HEADER {
+        return None;
+    }
+
+    let fields: Vec<(&str, &str)> = lines.filter_map(|line| line.split_once('=')).collect();
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    };
+
+    let ip = match field("address") {
+        Some(ip) => ip.parse().ok()?,
+        None => addr.ip(),
+    };
+
+    Some(Found {
+        address: SocketAddr::new(ip, field("port")?.parse().ok()?),
+        name: field("name")?.to_string(),
+        version: field("version").unwrap_or("unknown").to_string(),
+        busy: field("busy") == Some("true"),
+    })
+}
+
+/// The name of this machine, which clients can connect to with `--name`.
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+
+    match unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } {
+        0 => {
+            let length = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..length]).into_owned()
+        }
+        _ => String::from("unknown"),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from() -> SocketAddr {
+        "192.168.1.20:5000".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_answers() {
+        let answer = b"remouse server\nname=desk\nversion=0.2.1\nport=42069\nbusy=true\n";
+
+        assert_eq!(
+            parse_answer(answer, from()),
+            Some(Found {
+                address: "192.168.1.20:42069".parse().unwrap(),
+                name: "desk".to_string(),
+                version: "0.2.1".to_string(),
+                busy: true,
+            })
+        );
+    }
+
+    #[test]
+    fn prefers_the_address_the_server_is_bound_to() {
+        let answer = b"remouse server\nname=desk\nport=42069\nbusy=false\naddress=fd00::7\n";
+        let found = parse_answer(answer, from()).unwrap();
+
+        assert_eq!(found.address, "[fd00::7]:42069".parse().unwrap());
+        assert_eq!(found.version, "unknown");
+        assert!(!found.busy);
+    }
+
+    #[test]
+    fn rejects_incomplete_and_garbage_answers() {
+        let answers: &[&[u8]] = &[
+            b"remouse server\nname=desk\nversion=0.2.1\n",
+            b"remouse server\nname=desk\nport=99999\n",
+            b"remouse server\nport=42069\n",
+            b"remouse server\nname=desk\nport=42069\naddress=nowhere\n",
+            b"remouse discover",
+            b"",
+            b"\xff\xfe\x00garbage",
+            b"name=desk\nport=42069\n",
+        ];
+
+        for answer in answers {
+            assert_eq!(parse_answer(answer, from()), None);
+        }
+    }
+
+    #[test]
+    fn answers_can_be_parsed() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let ip = "10.0.0.5".parse().ok();
+        answer(&server, client.local_addr().unwrap(), ip, 4000, false);
+
+        let mut buf = [0; 512];
+        let (length, addr) = client.recv_from(&mut buf).unwrap();
+        let found = parse_answer(&buf[..length], addr).unwrap();
+        assert_eq!(found.address, "10.0.0.5:4000".parse().unwrap());
+        assert_eq!(found.name, hostname());
+        assert_eq!(found.version, env!("CARGO_PKG_VERSION"));
+    }
+}
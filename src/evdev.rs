@@ -0,0 +1,460 @@
+use crate::input::{Button, InputEvent, InputSource};
+use crate::keyboard::{all_keys, Key};
+use crate::uinput::{
+    ioctl, key_code, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, DEVICE_NAME, EV_ABS, EV_KEY,
+    EV_REL, REL_WHEEL, REL_X, REL_Y,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    mem::{size_of, zeroed},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::PathBuf,
+    slice,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const KEY_A: u16 = 30;
+const KEY_MAX: usize = 0x2ff;
+const BTN_TOUCH: u16 = 0x14a;
+/// Set on touchscreens and graphics tablets, whose positions are on the screen rather than relative.
+const INPUT_PROP_DIRECT: u16 = 0x01;
+
+const EVIOCGRAB: libc::c_ulong = 0x4004_4590;
+
+/// The mouse counts per millimetre touchpad movement is scaled to, which is what a 1000 DPI mouse reports.
+const COUNTS_PER_MM: f64 = 1000.0 / 25.4;
+
+/// How long to wait for keys held when the client starts to be released before grabbing the devices.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `EVIOCGNAME(len)`, which reads the device's name.
+fn eviocgname(len: usize) -> libc::c_ulong {
+    0x8000_4506 | ((len as libc::c_ulong) << 16)
+}
+
+/// `EVIOCGKEY(len)`, which reads a bitmap of the keys currently held.
+fn eviocgkey(len: usize) -> libc::c_ulong {
+    0x8000_4518 | ((len as libc::c_ulong) << 16)
+}
+
+/// `EVIOCGBIT(event_type, len)`, which reads a bitmap of the codes the device supports for an event type,
+/// or of the event types it supports if `event_type` is 0.
+fn eviocgbit(event_type: u16, len: usize) -> libc::c_ulong {
+    (0x8000_4520 + event_type as libc::c_ulong) | ((len as libc::c_ulong) << 16)
+}
+
+/// `EVIOCGPROP(len)`, which reads a bitmap of the device's properties.
+fn eviocgprop(len: usize) -> libc::c_ulong {
+    0x8000_4509 | ((len as libc::c_ulong) << 16)
+}
+
+/// `EVIOCGABS(axis)`, which reads the range and resolution of an absolute axis.
+fn eviocgabs(axis: u16) -> libc::c_ulong {
+    0x8018_4540 + axis as libc::c_ulong
+}
+
+/// Captures input by reading the keyboards, mice and touchpads in `/dev/input` directly,
+/// so every event is captured no matter which window has focus.
+/// Reading the devices needs read access to them, usually by being in the `input` group.
+pub struct EvdevSource {
+    devices: Vec<Device>,
+    /// Whether to grab the devices while forwarding.
+    exclusive: bool,
+    /// Whether the devices are grabbed, so their events aren't delivered to the client's machine.
+    grabbed: bool,
+}
+
+/// An input device being captured.
+struct Device {
+    file: File,
+    path: PathBuf,
+    /// The state needed to turn the device's positions into movement, if it is a touchpad.
+    touchpad: Option<Touchpad>,
+}
+
+/// Turns the absolute positions a touchpad reports into relative movement, like a mouse's.
+struct Touchpad {
+    /// The mouse counts each touchpad unit is worth on the X and Y axes.
+    scale: [f64; 2],
+    /// The last position on each axis since the finger touched down, if it has been reported.
+    last: [Option<i32>; 2],
+    /// The fractional counts left over, carried over to the next movement.
+    remainder: [f64; 2],
+}
+
+impl Touchpad {
+    fn new(file: &File) -> Touchpad {
+        // Scale by the touchpad's resolution if it reports one, so it moves as far as a mouse would
+        let scale = |axis: u16| {
+            let mut info: libc::input_absinfo = unsafe { zeroed() };
+            let _ = ioctl(
+                file.as_raw_fd(),
+                eviocgabs(axis),
+                &mut info as *mut libc::input_absinfo as libc::c_ulong,
+            );
+
+            match info.resolution {
+                resolution if resolution > 0 => COUNTS_PER_MM / resolution as f64,
+                _ => 1.0,
+            }
+        };
+
+        Touchpad {
+            scale: [scale(ABS_X), scale(ABS_Y)],
+            last: [None, None],
+            remainder: [0.0, 0.0],
+        }
+    }
+
+    /// Update an axis with a new position, returning how far it moved in mouse counts.
+    fn moved(&mut self, axis: usize, position: i32) -> i32 {
+        let last = match self.last[axis].replace(position) {
+            Some(last) => last,
+            None => return 0,
+        };
+
+        let distance = (position - last) as f64 * self.scale[axis] + self.remainder[axis];
+        self.remainder[axis] = distance.fract();
+        distance.trunc() as i32
+    }
+}
+
+impl Device {
+    /// Convert a kernel input event from the device into an `InputEvent`.
+    fn convert(&mut self, event: &libc::input_event) -> Option<InputEvent> {
+        let touchpad = match &mut self.touchpad {
+            Some(touchpad) => touchpad,
+            None => return convert_event(event),
+        };
+
+        match (event.type_, event.code) {
+            (EV_ABS, ABS_X) => match touchpad.moved(0, event.value) {
+                0 => None,
+                x => Some(InputEvent::Move(x, 0)),
+            },
+            (EV_ABS, ABS_Y) => match touchpad.moved(1, event.value) {
+                0 => None,
+                y => Some(InputEvent::Move(0, y)),
+            },
+            // Each touch starts from wherever the finger lands
+            (EV_KEY, BTN_TOUCH) => {
+                touchpad.last = [None, None];
+                None
+            }
+            _ => convert_event(event),
+        }
+    }
+}
+
+impl EvdevSource {
+    /// Open every keyboard, mouse and touchpad, grabbing them for exclusive access if `grab` is true.
+    pub fn open(grab: bool) -> io::Result<EvdevSource> {
+        let mut devices: Vec<Device> = Vec::new();
+        let mut last_error: Option<io::Error> = None;
+
+        let mut paths: Vec<_> = fs::read_dir("/dev/input")?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("event"))
+            })
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let file = match OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)
+            {
+                Ok(file) => file,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+
+            if let Some(kind) = device_kind(&file) {
+                debug!(device = path.display(); "capturing {} as a {}", path.display(), kind.name());
+                let touchpad = match kind {
+                    Kind::Touchpad => Some(Touchpad::new(&file)),
+                    _ => None,
+                };
+                devices.push(Device {
+                    file,
+                    path,
+                    touchpad,
+                });
+            }
+        }
+
+        if devices.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no keyboards or mice found")
+            }));
+        }
+
+        let mut source = EvdevSource {
+            devices,
+            exclusive: grab,
+            grabbed: false,
+        };
+
+        if grab {
+            source.grab()?;
+        }
+
+        Ok(source)
+    }
+
+    /// Grab every device, so their events are only delivered to the client.
+    fn grab(&mut self) -> io::Result<()> {
+        // Keys held when grabbing, such as the Enter used to start the client, would otherwise
+        // be released while grabbed and stay pressed on the client's machine.
+        let start = Instant::now();
+        while self.devices.iter().any(|device| holds_keys(&device.file))
+            && start.elapsed() < RELEASE_TIMEOUT
+        {
+            sleep(Duration::from_millis(10));
+        }
+
+        for device in &self.devices {
+            ioctl(device.file.as_raw_fd(), EVIOCGRAB, 1)?;
+        }
+        self.grabbed = true;
+
+        Ok(())
+    }
+}
+
+impl InputSource for EvdevSource {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events: Vec<InputEvent> = Vec::new();
+
+        // Wait up to a millisecond for input rather than spinning
+        let mut fds: Vec<libc::pollfd> = self
+            .devices
+            .iter()
+            .map(|device| libc::pollfd {
+                fd: device.file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 1) };
+
+        let mut index = 0;
+        self.devices.retain_mut(|device| {
+            let revents = fds[index].revents;
+            index += 1;
+
+            // An unplugged device is always ready to poll, so it has to be dropped to stop it spinning
+            let mut error = match revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) {
+                0 => None,
+                _ => Some(io::Error::from_raw_os_error(libc::ENODEV)),
+            };
+
+            while error.is_none() {
+                match read_event(&mut device.file) {
+                    Ok(Some(event)) => events.extend(device.convert(&event)),
+                    Ok(None) => break,
+                    Err(e) => error = Some(e),
+                }
+            }
+
+            match error {
+                Some(e) => {
+                    warn!(device = device.path.display(), error = e; "stopped capturing {}: {}", device.path.display(), e);
+                    false
+                }
+                None => true,
+            }
+        });
+
+        if self.devices.is_empty() {
+            error!("every input device has been removed");
+            events.push(InputEvent::Closed);
+        }
+
+        events
+    }
+
+    fn release(&mut self) {
+        if self.grabbed {
+            for device in &self.devices {
+                let _ = ioctl(device.file.as_raw_fd(), EVIOCGRAB, 0);
+            }
+            self.grabbed = false;
+        }
+    }
+
+    fn pause(&mut self) {
+        self.release();
+    }
+
+    fn resume(&mut self) {
+        if self.exclusive {
+            if let Err(e) = self.grab() {
+                warn!(error = e; "failed to grab input devices: {}", e);
+            }
+        }
+    }
+}
+
+/// The kinds of device which are captured.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Keyboard,
+    Mouse,
+    Touchpad,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Keyboard => "keyboard",
+            Kind::Mouse => "mouse",
+            Kind::Touchpad => "touchpad",
+        }
+    }
+}
+
+/// Find whether the device is a keyboard, mouse or touchpad, skipping the server's own virtual device.
+/// Devices which are more than one, such as a keyboard with a trackpoint, are captured as the first.
+fn device_kind(device: &File) -> Option<Kind> {
+    let fd = device.as_raw_fd();
+
+    let mut name = [0u8; 256];
+    if ioctl(
+        fd,
+        eviocgname(name.len()),
+        name.as_mut_ptr() as libc::c_ulong,
+    )
+    .is_ok()
+    {
+        let length = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        if &name[..length] == DEVICE_NAME.as_bytes() {
+            return None;
+        }
+    }
+
+    let mut event_types = [0u8; 4];
+    let mut keys = [0u8; KEY_MAX / 8 + 1];
+    let mut relative = [0u8; 2];
+    let mut absolute = [0u8; 8];
+    let mut properties = [0u8; 4];
+
+    let _ = ioctl(
+        fd,
+        eviocgbit(0, event_types.len()),
+        event_types.as_mut_ptr() as libc::c_ulong,
+    );
+    let _ = ioctl(
+        fd,
+        eviocgbit(EV_KEY, keys.len()),
+        keys.as_mut_ptr() as libc::c_ulong,
+    );
+    let _ = ioctl(
+        fd,
+        eviocgbit(EV_REL, relative.len()),
+        relative.as_mut_ptr() as libc::c_ulong,
+    );
+    let _ = ioctl(
+        fd,
+        eviocgbit(EV_ABS, absolute.len()),
+        absolute.as_mut_ptr() as libc::c_ulong,
+    );
+    let _ = ioctl(
+        fd,
+        eviocgprop(properties.len()),
+        properties.as_mut_ptr() as libc::c_ulong,
+    );
+
+    let keyboard = has_bit(&event_types, EV_KEY) && has_bit(&keys, KEY_A);
+    let mouse = has_bit(&event_types, EV_REL) && has_bit(&relative, REL_X);
+    // Joysticks have absolute axes too, but not touch or mouse buttons
+    let touchpad = has_bit(&event_types, EV_ABS)
+        && has_bit(&absolute, ABS_X)
+        && has_bit(&absolute, ABS_Y)
+        && (has_bit(&keys, BTN_TOUCH) || has_bit(&keys, BTN_LEFT))
+        && !has_bit(&properties, INPUT_PROP_DIRECT);
+
+    match (keyboard, mouse, touchpad) {
+        (true, _, _) => Some(Kind::Keyboard),
+        (_, true, _) => Some(Kind::Mouse),
+        (_, _, true) => Some(Kind::Touchpad),
+        _ => None,
+    }
+}
+
+/// Whether any key or button on the device is currently held.
+fn holds_keys(device: &File) -> bool {
+    let mut keys = [0u8; KEY_MAX / 8 + 1];
+    let _ = ioctl(
+        device.as_raw_fd(),
+        eviocgkey(keys.len()),
+        keys.as_mut_ptr() as libc::c_ulong,
+    );
+
+    keys.iter().any(|byte| *byte != 0)
+}
+
+fn has_bit(bits: &[u8], bit: u16) -> bool {
+    bits.get(bit as usize / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+/// Read the next event waiting on the device, if there is one.
+/// Fails if the device can't be read, such as when it has been unplugged.
+fn read_event(device: &mut File) -> io::Result<Option<libc::input_event>> {
+    let mut event: libc::input_event = unsafe { zeroed() };
+    let bytes = unsafe {
+        slice::from_raw_parts_mut(
+            &mut event as *mut libc::input_event as *mut u8,
+            size_of::<libc::input_event>(),
+        )
+    };
+
+    match device.read(bytes) {
+        Ok(length) if length == bytes.len() => Ok(Some(event)),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Convert a kernel input event into an `InputEvent`, skipping key repeats and anything the client doesn't forward.
+fn convert_event(event: &libc::input_event) -> Option<InputEvent> {
+    match (event.type_, event.code) {
+        (EV_REL, REL_X) => Some(InputEvent::Move(event.value, 0)),
+        (EV_REL, REL_Y) => Some(InputEvent::Move(0, event.value)),
+        (EV_REL, REL_WHEEL) => Some(InputEvent::Scroll(event.value)),
+        (EV_KEY, code) => {
+            let button = match code {
+                BTN_LEFT => Some(Button::Left),
+                BTN_RIGHT => Some(Button::Right),
+                BTN_MIDDLE => Some(Button::Middle),
+                _ => None,
+            };
+
+            match (button, event.value) {
+                (Some(button), 1) => Some(InputEvent::ButtonDown(button)),
+                (Some(button), 0) => Some(InputEvent::ButtonUp(button)),
+                (Some(_), _) => None,
+                (None, 1) => key_from_code(code).map(InputEvent::KeyDown),
+                (None, 0) => key_from_code(code).map(InputEvent::KeyUp),
+                (None, _) => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Convert a Linux input event code into a `Key`, the reverse of `uinput::key_code()`.
+fn key_from_code(code: u16) -> Option<Key> {
+    all_keys().find(|key| key_code(*key) == code)
+}
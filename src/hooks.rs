@@ -0,0 +1,141 @@
+use crate::address::canonical_ip;
+use crate::session::Session;
+use std::{process::Command, thread};
+
+/// Commands run when clients connect and disconnect, such as to disable the screensaver.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hooks {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+}
+
+impl Hooks {
+    /// Run the connect command, if there is one, for a session which just started.
+    pub fn connected(&self, session: &Session) {
+        if let Some(command) = &self.on_connect {
+            run(command, session, "connect", None);
+        }
+    }
+
+    /// Run the disconnect command, if there is one, for a session which just ended.
+    /// `reason` is `closed`, `timeout`, `kicked` or `shutdown`.
+    pub fn disconnected(&self, session: &Session, reason: &str) {
+        if let Some(command) = &self.on_disconnect {
+            run(command, session, "disconnect", Some(reason));
+        }
+    }
+}
+
+/// Run `command` through the shell in the background, describing the session in environment variables.
+fn run(command: &str, session: &Session, event: &str, reason: Option<&str>) {
+    let mut process = match cfg!(windows) {
+        true => {
+            let mut process = Command::new("cmd");
+            process.arg("/C").arg(command);
+            process
+        }
+        false => {
+            let mut process = Command::new("sh");
+            process.arg("-c").arg(command);
+            process
+        }
+    };
+
+    process
+        .env("REMOUSE_EVENT", event)
+        .env(
+            "REMOUSE_PEER",
+            canonical_ip(session.address.ip()).to_string(),
+        )
+        .env("REMOUSE_PEER_PORT", session.address.port().to_string())
+        .env("REMOUSE_SESSION", session.id.to_string());
+    if let Some(reason) = reason {
+        process.env("REMOUSE_REASON", reason);
+    }
+
+    let command = command.to_string();
+    let event = event.to_string();
+    let session = session.id;
+    match process.spawn() {
+        // Wait for the command on another thread so the server isn't held up and no zombie is left behind
+        Ok(mut child) => {
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => warn!(
+                    event = "hook_failed", session = session, command = command;
+                    "{} hook `{}` exited with {}", event, command, status
+                ),
+                Ok(_) => (),
+                Err(e) => warn!(
+                    event = "hook_failed", session = session, error = e;
+                    "failed to wait for {} hook `{}`: {}", event, command, e
+                ),
+            });
+        }
+        Err(e) => warn!(
+            event = "hook_failed", session = session, error = e;
+            "failed to run {} hook `{}`: {}", event, command, e
+        ),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::ratelimit::Limits;
+    use std::{fs, path::Path, process, time::Duration};
+
+    /// Wait for a hook, which runs in the background, to write `path`.
+    fn wait_for(path: &Path) -> String {
+        for _ in 0..200 {
+            if let Ok(contents) = fs::read_to_string(path) {
+                return contents;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the hook never wrote {}", path.display());
+    }
+
+    /// A hook command which saves the `REMOUSE_` variables it was given to `path`.
+    fn save_environment(path: &Path) -> String {
+        let path = path.display();
+        format!(
+            "env | grep ^REMOUSE_ | sort > {0}.tmp && mv {0}.tmp {0}",
+            path
+        )
+    }
+
+    #[test]
+    fn describes_the_session_to_hooks() {
+        let dir = std::env::temp_dir().join(format!("remouse-test-{}-hooks", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (connect, disconnect) = (dir.join("connect"), dir.join("disconnect"));
+
+        let hooks = Hooks {
+            on_connect: Some(save_environment(&connect)),
+            on_disconnect: Some(save_environment(&disconnect)),
+        };
+        let address = "[::ffff:192.168.1.5]:40000".parse().unwrap();
+        let session = Session::new(7, address, &Limits::parse(None, None, None).unwrap());
+
+        hooks.connected(&session);
+        assert_eq!(
+            wait_for(&connect),
+            "REMOUSE_EVENT=connect\n\
+             REMOUSE_PEER=192.168.1.5\n\
+             REMOUSE_PEER_PORT=40000\n\
+             REMOUSE_SESSION=7\n"
+        );
+
+        hooks.disconnected(&session, "timeout");
+        assert_eq!(
+            wait_for(&disconnect),
+            "REMOUSE_EVENT=disconnect\n\
+             REMOUSE_PEER=192.168.1.5\n\
+             REMOUSE_PEER_PORT=40000\n\
+             REMOUSE_REASON=timeout\n\
+             REMOUSE_SESSION=7\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
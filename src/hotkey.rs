@@ -0,0 +1,188 @@
+use crate::input::InputEvent;
+use crate::keyboard::Key;
+use std::time::{Duration, Instant};
+
+/// The hotkey which disconnects the client if none is given with `--release-hotkey`.
+pub const DEFAULT_RELEASE: &str = "ctrl+alt+shift+r";
+
+/// The hotkey which pauses and resumes forwarding if none is given with `--pause-hotkey`.
+pub const DEFAULT_PAUSE: &str = "ctrl+alt+shift+p";
+
+/// How soon the second press of a double tap has to follow the first.
+const DOUBLE_TAP_INTERVAL: Duration = Duration::from_millis(400);
+
+/// A key combination which the client acts on itself.
+pub struct Hotkey {
+    trigger: Trigger,
+    /// When the key of a double tap was last pressed, if no other key has been pressed since.
+    last_tap: Option<Instant>,
+}
+
+enum Trigger {
+    /// Every key is held at once. Each entry lists the keys which count for it,
+    /// so that modifier aliases such as `ctrl` match both sides of the keyboard.
+    Chord(Vec<Vec<Key>>),
+    /// The key is pressed twice in quick succession.
+    DoubleTap(Key),
+}
+
+impl Hotkey {
+    /// Parse a hotkey, which is either a chord of key names joined by `+` such as `ctrl+alt+shift+r`,
+    /// or `double:` followed by a key name such as `double:rightctrl`.
+    /// Keys are named as in remap profiles.
+    pub fn parse(hotkey: &str) -> Result<Hotkey, String> {
+        let trigger = match hotkey.strip_prefix("double:") {
+            Some(name) => {
+                Trigger::DoubleTap(Key::from_name(name).ok_or(format!("unknown key {}", name))?)
+            }
+            None => Trigger::Chord(
+                hotkey
+                    .split('+')
+                    .map(|name| chord_keys(name.trim()).ok_or(format!("unknown key {}", name)))
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+
+        Ok(Hotkey {
+            trigger,
+            last_tap: None,
+        })
+    }
+
+    /// Update the hotkey with an input event, given the keys held after it.
+    /// Returns whether the event pressed the hotkey.
+    pub fn pressed(&mut self, event: &InputEvent, held: &[Key]) -> bool {
+        let key = match event {
+            InputEvent::KeyDown(key) => *key,
+            _ => return false,
+        };
+
+        match &self.trigger {
+            Trigger::Chord(keys) => {
+                keys.iter().any(|entry| entry.contains(&key))
+                    && keys
+                        .iter()
+                        .all(|entry| entry.iter().any(|key| held.contains(key)))
+            }
+            Trigger::DoubleTap(tap) if *tap == key => {
+                match self.last_tap.take() {
+                    Some(time) if time.elapsed() < DOUBLE_TAP_INTERVAL => return true,
+                    _ => self.last_tap = Some(Instant::now()),
+                }
+
+                false
+            }
+            Trigger::DoubleTap(_) => {
+                self.last_tap = None;
+                false
+            }
+        }
+    }
+}
+
+/// Find the keys which count for a key named in a chord.
+/// Modifiers match the keys on both sides unless the name says which side.
+fn chord_keys(name: &str) -> Option<Vec<Key>> {
+    let key = Key::from_name(name)?;
+    let right = match key {
+        Key::LeftShift => Some(Key::RightShift),
+        Key::LeftCtrl => Some(Key::RightCtrl),
+        Key::LeftAlt => Some(Key::RightAlt),
+        Key::LeftMeta => Some(Key::RightMeta),
+        _ => None,
+    };
+
+    match right {
+        Some(right) if !name.to_ascii_lowercase().starts_with("left") => Some(vec![key, right]),
+        _ => Some(vec![key]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Press `keys` in order, returning whether each press triggered the hotkey.
+    fn press(hotkey: &mut Hotkey, keys: &[Key]) -> Vec<bool> {
+        let mut held = Vec::new();
+
+        keys.iter()
+            .map(|key| {
+                held.push(*key);
+                hotkey.pressed(&InputEvent::KeyDown(*key), &held)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chords_trigger_when_the_last_key_is_pressed() {
+        let mut hotkey = Hotkey::parse("ctrl+alt+R").unwrap();
+
+        assert_eq!(
+            press(&mut hotkey, &[Key::LeftCtrl, Key::LeftAlt, Key::R]),
+            vec![false, false, true]
+        );
+        assert_eq!(
+            press(&mut hotkey, &[Key::R, Key::RightAlt, Key::RightCtrl]),
+            vec![false, false, true]
+        );
+        assert_eq!(
+            press(&mut hotkey, &[Key::LeftCtrl, Key::R]),
+            vec![false, false]
+        );
+    }
+
+    #[test]
+    fn sided_modifiers_only_match_that_side() {
+        let mut hotkey = Hotkey::parse("leftctrl+p").unwrap();
+
+        assert_eq!(
+            press(&mut hotkey, &[Key::RightCtrl, Key::P]),
+            vec![false, false]
+        );
+        assert_eq!(
+            press(&mut hotkey, &[Key::LeftCtrl, Key::P]),
+            vec![false, true]
+        );
+    }
+
+    #[test]
+    fn releasing_keys_does_not_trigger() {
+        let mut hotkey = Hotkey::parse("ctrl+r").unwrap();
+
+        assert!(!hotkey.pressed(&InputEvent::KeyUp(Key::LeftCtrl), &[Key::LeftCtrl, Key::R]));
+    }
+
+    #[test]
+    fn double_taps_trigger_on_the_second_press() {
+        let mut hotkey = Hotkey::parse("double:rightctrl").unwrap();
+
+        assert_eq!(
+            press(
+                &mut hotkey,
+                &[Key::RightCtrl, Key::RightCtrl, Key::RightCtrl]
+            ),
+            vec![false, true, false]
+        );
+    }
+
+    #[test]
+    fn double_taps_are_interrupted_by_other_keys_and_time() {
+        let mut hotkey = Hotkey::parse("double:rightctrl").unwrap();
+
+        assert_eq!(
+            press(&mut hotkey, &[Key::RightCtrl, Key::C, Key::RightCtrl]),
+            vec![false, false, false]
+        );
+
+        hotkey.last_tap = Some(Instant::now() - DOUBLE_TAP_INTERVAL);
+        assert_eq!(press(&mut hotkey, &[Key::RightCtrl]), vec![false]);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(Hotkey::parse("ctrl+hyper").is_err());
+        assert!(Hotkey::parse("ctrl++r").is_err());
+        assert!(Hotkey::parse("double:").is_err());
+    }
+}
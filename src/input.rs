@@ -0,0 +1,386 @@
+#[cfg(windows)]
+use crate::keyboard::minifb_to_keys;
+use crate::keyboard::Key;
+use crate::layout::Layout;
+#[cfg(windows)]
+use enigo::{Enigo, MouseControllable};
+#[cfg(windows)]
+use minifb::{CursorStyle, Window, WindowOptions};
+#[cfg(windows)]
+use multiinput::{DeviceType, RawEvent, RawInputManager, State};
+use std::{
+    io::{stdin, BufRead},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+/// A mouse button which the client forwards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+}
+
+impl Button {
+    /// The bit representing the button in the mouse button flags of an input packet.
+    pub fn flag(self) -> u8 {
+        match self {
+            Button::Left => 0b0000_0001,
+            Button::Right => 0b0000_0010,
+            Button::Middle => 0b0000_0100,
+        }
+    }
+
+    /// Parse a button from its name, ignoring case.
+    pub fn from_name(name: &str) -> Option<Button> {
+        match name.to_ascii_lowercase().as_str() {
+            "left" => Some(Button::Left),
+            "right" => Some(Button::Right),
+            "middle" => Some(Button::Middle),
+            _ => None,
+        }
+    }
+}
+
+/// An input event captured on the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    /// The mouse moved by the given number of counts.
+    Move(i32, i32),
+    ButtonDown(Button),
+    ButtonUp(Button),
+    /// The mouse wheel scrolled, where positive distances scroll up.
+    Scroll(i32),
+    /// A key was pressed, identified by its physical position.
+    KeyDown(Key),
+    KeyUp(Key),
+    /// The source has no more input, so the client should disconnect.
+    Closed,
+}
+
+/// Something which captures the user's input on the client.
+pub trait InputSource {
+    /// Take the events which have happened since the last call, in order.
+    /// This shouldn't block for long, as the client sends packets between calls.
+    fn poll(&mut self) -> Vec<InputEvent>;
+
+    /// Give the input back to the client's machine before the client quits.
+    fn release(&mut self) {}
+
+    /// Give the input back to the client's machine while forwarding is paused.
+    /// The source should still be polled, so the client can see the hotkey to resume.
+    fn pause(&mut self) {}
+
+    /// Take the input again after `pause()`.
+    fn resume(&mut self) {}
+}
+
+/// The input capture backends which can be selected with `--capture`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    #[cfg(windows)]
+    Window,
+    Stdin,
+    #[cfg(target_os = "linux")]
+    Evdev,
+}
+
+impl Capture {
+    /// Parse a capture backend from its command line name.
+    pub fn from_name(name: &str) -> Option<Capture> {
+        match name {
+            #[cfg(windows)]
+            "window" => Some(Capture::Window),
+            "stdin" => Some(Capture::Stdin),
+            #[cfg(target_os = "linux")]
+            "evdev" => Some(Capture::Evdev),
+            _ => None,
+        }
+    }
+
+    /// The backend used if none is given with `--headless`, or anywhere but Windows, which doesn't need a window.
+    pub fn headless() -> Capture {
+        #[cfg(target_os = "linux")]
+        let capture = Capture::Evdev;
+        #[cfg(not(target_os = "linux"))]
+        let capture = Capture::Stdin;
+
+        capture
+    }
+
+    /// Create the input source for this backend.
+    /// `override_movement` only applies to the window and `grab` only applies to evdev.
+    // Backends are only built on the platforms they support, so some options go unused elsewhere
+    #[allow(unused_variables)]
+    pub fn create(
+        self,
+        layout: Layout,
+        override_movement: bool,
+        grab: bool,
+    ) -> std::io::Result<Box<dyn InputSource>> {
+        match self {
+            #[cfg(windows)]
+            Capture::Window => Ok(Box::new(WindowSource::new(layout, override_movement))),
+            Capture::Stdin => Ok(Box::new(StdinSource::default())),
+            #[cfg(target_os = "linux")]
+            Capture::Evdev => Ok(Box::new(crate::evdev::EvdevSource::open(grab)?)),
+        }
+    }
+}
+
+/// Captures the mouse with `multiinput` and the keyboard through a small `minifb` window,
+/// which needs to have focus for keys to be captured. `multiinput` only supports Windows.
+#[cfg(windows)]
+pub struct WindowSource {
+    window: Window,
+    input_manager: RawInputManager,
+    output_manager: Enigo,
+    /// The client's keyboard layout, used to find the physical position of the keys pressed.
+    layout: Layout,
+    /// Whether to lock the cursor to the top left of the screen, inside the window, so it keeps focus.
+    override_movement: bool,
+    /// Whether forwarding is paused, in which case the cursor is left alone.
+    paused: bool,
+    /// The keys held when the window was last checked.
+    keys: Vec<Key>,
+}
+
+#[cfg(windows)]
+impl WindowSource {
+    /// Open the key capture window and start listening for mouse events.
+    pub fn new(layout: Layout, override_movement: bool) -> WindowSource {
+        let mut input_manager = RawInputManager::new().unwrap();
+        input_manager.register_devices(DeviceType::Mice);
+
+        let mut window = Window::new("Remouse", 100, 100, WindowOptions::default()).unwrap();
+        window.update();
+        window.set_position(-50, -50);
+        window.set_cursor_style(CursorStyle::Arrow);
+
+        WindowSource {
+            window,
+            input_manager,
+            output_manager: Enigo::new(),
+            layout,
+            override_movement,
+            paused: false,
+            keys: Vec::new(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl InputSource for WindowSource {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events: Vec<InputEvent> = self
+            .input_manager
+            .get_events()
+            .filter_map(|event| match event {
+                RawEvent::MouseMoveEvent(_, x, y) => Some(InputEvent::Move(x, y)),
+                RawEvent::MouseWheelEvent(_, direction) => {
+                    Some(InputEvent::Scroll(direction.signum() as i32))
+                }
+                RawEvent::MouseButtonEvent(_, button, state) => {
+                    let button = match button {
+                        multiinput::MouseButton::Left => Button::Left,
+                        multiinput::MouseButton::Right => Button::Right,
+                        multiinput::MouseButton::Middle => Button::Middle,
+                        _ => return None,
+                    };
+
+                    match state {
+                        State::Pressed => Some(InputEvent::ButtonDown(button)),
+                        State::Released => Some(InputEvent::ButtonUp(button)),
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+
+        // Keep the cursor inside the key capture window so keys are always captured
+        if self.override_movement
+            && !self.paused
+            && events.iter().any(|e| matches!(e, InputEvent::Move(..)))
+        {
+            self.output_manager.mouse_move_to(0, 0);
+        }
+
+        let keys = minifb_to_keys(&self.window.get_keys().unwrap_or_default(), self.layout);
+        for key in &self.keys {
+            if !keys.contains(key) {
+                events.push(InputEvent::KeyUp(*key));
+            }
+        }
+        for key in &keys {
+            if !self.keys.contains(key) {
+                events.push(InputEvent::KeyDown(*key));
+            }
+        }
+        self.keys = keys;
+
+        self.window.update();
+        events
+    }
+
+    /// Stop locking the cursor. The window still only sees keys while it has focus, so once another
+    /// window is clicked, the hotkey to resume isn't seen until the window is focused again.
+    fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    fn resume(&mut self) {
+        self.paused = false;
+    }
+}
+
+/// Reads input events from standard input, one per line, so the client can be scripted or run without a display.
+///
+/// The commands are `move X Y`, `scroll DISTANCE`, `button down|up left|right|middle` and `key down|up KEY`,
+/// where keys are named as in remap profiles. Blank lines and lines starting with `#` are skipped.
+/// The client disconnects when standard input is closed.
+#[derive(Default)]
+pub struct StdinSource {
+    /// Receives events from the thread reading standard input, which starts on the first poll
+    /// so that standard input can still be used to enter the pairing PIN.
+    events: Option<Receiver<InputEvent>>,
+}
+
+impl InputSource for StdinSource {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let receiver = self.events.get_or_insert_with(|| {
+            let (sender, receiver) = channel();
+
+            thread::spawn(move || {
+                for line in stdin().lock().lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+
+                    match parse_command(line) {
+                        Ok(event) => {
+                            if sender.send(event).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+            });
+
+            receiver
+        });
+
+        // Wait up to a millisecond for input rather than spinning.
+        // Stop after a button or key changes, so a press and release on consecutive lines are sent separately.
+        let mut events: Vec<InputEvent> = Vec::new();
+        let mut next = receiver.recv_timeout(Duration::from_millis(1));
+
+        loop {
+            match next {
+                Ok(event) => {
+                    let moved = matches!(event, InputEvent::Move(..));
+                    events.push(event);
+                    if !moved {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    events.push(InputEvent::Closed);
+                    break;
+                }
+            }
+
+            next = receiver.recv_timeout(Duration::from_millis(0));
+        }
+
+        events
+    }
+}
+
+/// Parse a line of input given to `StdinSource`.
+fn parse_command(line: &str) -> Result<InputEvent, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let number = |word: &str| {
+        word.parse::<i32>()
+            .map_err(|_| format!("invalid number {}", word))
+    };
+
+    match words.as_slice() {
+        ["move", x, y] => Ok(InputEvent::Move(number(x)?, number(y)?)),
+        ["scroll", distance] => Ok(InputEvent::Scroll(number(distance)?)),
+        ["button", action, name] => {
+            let button = Button::from_name(name).ok_or(format!("unknown button {}", name))?;
+            match *action {
+                "down" => Ok(InputEvent::ButtonDown(button)),
+                "up" => Ok(InputEvent::ButtonUp(button)),
+                _ => Err(format!("expected down or up, found {}", action)),
+            }
+        }
+        ["key", action, name] => {
+            let key = Key::from_name(name).ok_or(format!("unknown key {}", name))?;
+            match *action {
+                "down" => Ok(InputEvent::KeyDown(key)),
+                "up" => Ok(InputEvent::KeyUp(key)),
+                _ => Err(format!("expected down or up, found {}", action)),
+            }
+        }
+        _ => Err(format!("unknown command {}", line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("move 3 -4"), Ok(InputEvent::Move(3, -4)));
+        assert_eq!(parse_command("  scroll   -2 "), Ok(InputEvent::Scroll(-2)));
+        assert_eq!(
+            parse_command("button down Left"),
+            Ok(InputEvent::ButtonDown(Button::Left))
+        );
+        assert_eq!(
+            parse_command("button up middle"),
+            Ok(InputEvent::ButtonUp(Button::Middle))
+        );
+        assert_eq!(
+            parse_command("key down ctrl"),
+            Ok(InputEvent::KeyDown(Key::LeftCtrl))
+        );
+        assert_eq!(parse_command("key up Z"), Ok(InputEvent::KeyUp(Key::Z)));
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert_eq!(
+            parse_command("move 1 x"),
+            Err("invalid number x".to_string())
+        );
+        assert_eq!(
+            parse_command("move 1"),
+            Err("unknown command move 1".to_string())
+        );
+        assert_eq!(
+            parse_command("button press left"),
+            Err("expected down or up, found press".to_string())
+        );
+        assert_eq!(
+            parse_command("button down back"),
+            Err("unknown button back".to_string())
+        );
+        assert_eq!(
+            parse_command("key down hyper"),
+            Err("unknown key hyper".to_string())
+        );
+        assert!(parse_command("").is_err());
+    }
+}
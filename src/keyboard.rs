@@ -1,4 +1,6 @@
-/// Convert a `Vec<Key>` into i64 flags.
+use crate::layout::Layout;
+
+/// Convert the physical keys being held into u64 flags, for servers which don't read the held keys.
 /// Mapping is as follows:
 ///
 /// ```
@@ -30,161 +32,446 @@
 /// [CapsLock]               [62]
 /// [Alt]                    [63]
 /// ```
-pub fn pressed_keys_to_flags(pressed_keys: &Vec<minifb::Key>) -> Option<u64> {
-    let mut flags: u64 = 0;
-    for key in pressed_keys {
-        flags = flags
-            + match key {
-                minifb::Key::A => 1 << 0,
-                minifb::Key::B => 1 << 1,
-                minifb::Key::C => 1 << 2,
-                minifb::Key::D => 1 << 3,
-                minifb::Key::E => 1 << 4,
-                minifb::Key::F => 1 << 5,
-                minifb::Key::G => 1 << 6,
-                minifb::Key::H => 1 << 7,
-                minifb::Key::I => 1 << 8,
-                minifb::Key::J => 1 << 9,
-                minifb::Key::K => 1 << 10,
-                minifb::Key::L => 1 << 11,
-                minifb::Key::M => 1 << 12,
-                minifb::Key::N => 1 << 13,
-                minifb::Key::O => 1 << 14,
-                minifb::Key::P => 1 << 15,
-                minifb::Key::Q => 1 << 16,
-                minifb::Key::R => 1 << 17,
-                minifb::Key::S => 1 << 18,
-                minifb::Key::T => 1 << 19,
-                minifb::Key::U => 1 << 20,
-                minifb::Key::V => 1 << 21,
-                minifb::Key::W => 1 << 22,
-                minifb::Key::X => 1 << 23,
-                minifb::Key::Y => 1 << 24,
-                minifb::Key::Z => 1 << 25,
-                minifb::Key::Key0 => 1 << 26,
-                minifb::Key::Key1 => 1 << 27,
-                minifb::Key::Key2 => 1 << 28,
-                minifb::Key::Key3 => 1 << 29,
-                minifb::Key::Key4 => 1 << 30,
-                minifb::Key::Key5 => 1 << 31,
-                minifb::Key::Key6 => 1 << 32,
-                minifb::Key::Key7 => 1 << 33,
-                minifb::Key::Key8 => 1 << 34,
-                minifb::Key::Key9 => 1 << 35,
-                minifb::Key::Space => 1 << 36,
-                minifb::Key::Enter => 1 << 37,
-                minifb::Key::LeftShift => 1 << 38,
-                minifb::Key::RightShift => 1 << 38,
-                minifb::Key::LeftCtrl => 1 << 39,
-                minifb::Key::RightCtrl => 1 << 39,
-                minifb::Key::Down => 1 << 40,
-                minifb::Key::Left => 1 << 41,
-                minifb::Key::Right => 1 << 42,
-                minifb::Key::Up => 1 << 43,
-                minifb::Key::Apostrophe => 1 << 44,
-                minifb::Key::Backquote => 1 << 45,
-                minifb::Key::Backslash => 1 << 46,
-                minifb::Key::Comma => 1 << 47,
-                minifb::Key::Equal => 1 << 48,
-                minifb::Key::LeftBracket => 1 << 49,
-                minifb::Key::Minus => 1 << 50,
-                minifb::Key::Period => 1 << 51,
-                minifb::Key::RightBracket => 1 << 52,
-                minifb::Key::Semicolon => 1 << 53,
-                minifb::Key::Slash => 1 << 54,
-                minifb::Key::Backspace => 1 << 55,
-                minifb::Key::Delete => 1 << 56,
-                minifb::Key::End => 1 << 57,
-                minifb::Key::Home => 1 << 58,
-                minifb::Key::PageDown => 1 << 59,
-                minifb::Key::PageUp => 1 << 60,
-                minifb::Key::Tab => 1 << 61,
-                minifb::Key::CapsLock => 1 << 62,
-                minifb::Key::LeftAlt => 1 << 63,
-                minifb::Key::RightAlt => 1 << 63,
-                minifb::Key::Escape => return None,
-                _ => 0,
-            };
-    }
+///
+/// Letter flags are named after the letter the key types on the client's `layout`, and the right
+/// modifiers share the flags of the left ones. Keys without a flag are left out.
+pub fn keys_to_flags(keys: &[Key], layout: Layout) -> u64 {
+    keys.iter()
+        .map(|key| {
+            let letter = layout
+                .character(*key)
+                .and_then(|character| Layout::Us.key(character))
+                .filter(|letter| (0x04..=0x1d).contains(&letter.usage()));
+
+            match letter.unwrap_or(*key) {
+                Key::RightShift => Key::LeftShift,
+                Key::RightCtrl => Key::LeftCtrl,
+                Key::RightAlt => Key::LeftAlt,
+                key => key,
+            }
+        })
+        .filter_map(|key| KEYS.iter().position(|k| *k == key))
+        .fold(0, |flags, index| flags | 1 << index)
+}
+
+/// Convert the keys held according to minifb into the physical keys being held.
+/// Unlike the flags, this distinguishes left and right modifiers and includes keys without a flag.
+///
+/// minifb names letter keys after the letter they type on the client's layout,
+/// so `layout` is used to find their physical position.
+#[cfg(windows)]
+pub fn minifb_to_keys(pressed_keys: &[minifb::Key], layout: Layout) -> Vec<Key> {
+    pressed_keys
+        .iter()
+        .filter_map(|key| minifb_to_key(*key))
+        .map(|key| match key.usage() {
+            0x04..=0x1d => Layout::Us
+                .character(key)
+                .and_then(|character| layout.key(character))
+                .unwrap_or(key),
+            _ => key,
+        })
+        .collect()
+}
 
-    Some(flags)
+/// Convert a `minifb::Key` into the `Key` with the same name.
+#[cfg(windows)]
+fn minifb_to_key(key: minifb::Key) -> Option<Key> {
+    match key {
+        minifb::Key::A => Some(Key::A),
+        minifb::Key::B => Some(Key::B),
+        minifb::Key::C => Some(Key::C),
+        minifb::Key::D => Some(Key::D),
+        minifb::Key::E => Some(Key::E),
+        minifb::Key::F => Some(Key::F),
+        minifb::Key::G => Some(Key::G),
+        minifb::Key::H => Some(Key::H),
+        minifb::Key::I => Some(Key::I),
+        minifb::Key::J => Some(Key::J),
+        minifb::Key::K => Some(Key::K),
+        minifb::Key::L => Some(Key::L),
+        minifb::Key::M => Some(Key::M),
+        minifb::Key::N => Some(Key::N),
+        minifb::Key::O => Some(Key::O),
+        minifb::Key::P => Some(Key::P),
+        minifb::Key::Q => Some(Key::Q),
+        minifb::Key::R => Some(Key::R),
+        minifb::Key::S => Some(Key::S),
+        minifb::Key::T => Some(Key::T),
+        minifb::Key::U => Some(Key::U),
+        minifb::Key::V => Some(Key::V),
+        minifb::Key::W => Some(Key::W),
+        minifb::Key::X => Some(Key::X),
+        minifb::Key::Y => Some(Key::Y),
+        minifb::Key::Z => Some(Key::Z),
+        minifb::Key::Key0 => Some(Key::Key0),
+        minifb::Key::Key1 => Some(Key::Key1),
+        minifb::Key::Key2 => Some(Key::Key2),
+        minifb::Key::Key3 => Some(Key::Key3),
+        minifb::Key::Key4 => Some(Key::Key4),
+        minifb::Key::Key5 => Some(Key::Key5),
+        minifb::Key::Key6 => Some(Key::Key6),
+        minifb::Key::Key7 => Some(Key::Key7),
+        minifb::Key::Key8 => Some(Key::Key8),
+        minifb::Key::Key9 => Some(Key::Key9),
+        minifb::Key::Escape => Some(Key::Escape),
+        minifb::Key::F1 => Some(Key::F1),
+        minifb::Key::F2 => Some(Key::F2),
+        minifb::Key::F3 => Some(Key::F3),
+        minifb::Key::F4 => Some(Key::F4),
+        minifb::Key::F5 => Some(Key::F5),
+        minifb::Key::F6 => Some(Key::F6),
+        minifb::Key::F7 => Some(Key::F7),
+        minifb::Key::F8 => Some(Key::F8),
+        minifb::Key::F9 => Some(Key::F9),
+        minifb::Key::F10 => Some(Key::F10),
+        minifb::Key::F11 => Some(Key::F11),
+        minifb::Key::F12 => Some(Key::F12),
+        minifb::Key::Space => Some(Key::Space),
+        minifb::Key::Enter => Some(Key::Enter),
+        minifb::Key::LeftShift => Some(Key::LeftShift),
+        minifb::Key::RightShift => Some(Key::RightShift),
+        minifb::Key::LeftCtrl => Some(Key::LeftCtrl),
+        minifb::Key::RightCtrl => Some(Key::RightCtrl),
+        minifb::Key::Down => Some(Key::Down),
+        minifb::Key::Left => Some(Key::Left),
+        minifb::Key::Right => Some(Key::Right),
+        minifb::Key::Up => Some(Key::Up),
+        minifb::Key::Apostrophe => Some(Key::Apostrophe),
+        minifb::Key::Backquote => Some(Key::Backquote),
+        minifb::Key::Backslash => Some(Key::Backslash),
+        minifb::Key::Comma => Some(Key::Comma),
+        minifb::Key::Equal => Some(Key::Equal),
+        minifb::Key::LeftBracket => Some(Key::LeftBracket),
+        minifb::Key::Minus => Some(Key::Minus),
+        minifb::Key::Period => Some(Key::Period),
+        minifb::Key::RightBracket => Some(Key::RightBracket),
+        minifb::Key::Semicolon => Some(Key::Semicolon),
+        minifb::Key::Slash => Some(Key::Slash),
+        minifb::Key::Backspace => Some(Key::Backspace),
+        minifb::Key::Delete => Some(Key::Delete),
+        minifb::Key::End => Some(Key::End),
+        minifb::Key::Home => Some(Key::Home),
+        minifb::Key::PageDown => Some(Key::PageDown),
+        minifb::Key::PageUp => Some(Key::PageUp),
+        minifb::Key::Tab => Some(Key::Tab),
+        minifb::Key::CapsLock => Some(Key::CapsLock),
+        minifb::Key::LeftAlt => Some(Key::LeftAlt),
+        minifb::Key::RightAlt => Some(Key::RightAlt),
+        minifb::Key::LeftSuper => Some(Key::LeftMeta),
+        minifb::Key::RightSuper => Some(Key::RightMeta),
+        _ => None,
+    }
 }
 
-/// Convert a `Vec<bool>` into a `Vec<enigo::Key>` with every key currently being held.
-/// Mappings are documented on the reverse, `pressed_keys_to_flags()`.
-pub fn flags_to_pressed_keys(flags: &Vec<bool>) -> Vec<enigo::Key> {
-    let mut held_keys: Vec<enigo::Key> = Vec::new();
+/// Convert a `Vec<bool>` into a `Vec<Key>` with every key currently being held.
+/// Mappings are documented on the reverse, `keys_to_flags()`.
+pub fn flags_to_pressed_keys(flags: &Vec<bool>) -> Vec<Key> {
+    let mut held_keys: Vec<Key> = Vec::new();
     for i in 0..flags.len() {
         if flags[i] {
-            held_keys.push(match i {
-                0 => enigo::Key::Layout('a'),
-                1 => enigo::Key::Layout('b'),
-                2 => enigo::Key::Layout('c'),
-                3 => enigo::Key::Layout('d'),
-                4 => enigo::Key::Layout('e'),
-                5 => enigo::Key::Layout('f'),
-                6 => enigo::Key::Layout('g'),
-                7 => enigo::Key::Layout('h'),
-                8 => enigo::Key::Layout('i'),
-                9 => enigo::Key::Layout('j'),
-                10 => enigo::Key::Layout('k'),
-                11 => enigo::Key::Layout('l'),
-                12 => enigo::Key::Layout('m'),
-                13 => enigo::Key::Layout('n'),
-                14 => enigo::Key::Layout('o'),
-                15 => enigo::Key::Layout('p'),
-                16 => enigo::Key::Layout('q'),
-                17 => enigo::Key::Layout('r'),
-                18 => enigo::Key::Layout('s'),
-                19 => enigo::Key::Layout('t'),
-                20 => enigo::Key::Layout('u'),
-                21 => enigo::Key::Layout('v'),
-                22 => enigo::Key::Layout('w'),
-                23 => enigo::Key::Layout('x'),
-                24 => enigo::Key::Layout('y'),
-                25 => enigo::Key::Layout('z'),
-                26 => enigo::Key::Layout('0'),
-                27 => enigo::Key::Layout('1'),
-                28 => enigo::Key::Layout('2'),
-                29 => enigo::Key::Layout('3'),
-                30 => enigo::Key::Layout('4'),
-                31 => enigo::Key::Layout('5'),
-                32 => enigo::Key::Layout('6'),
-                33 => enigo::Key::Layout('7'),
-                34 => enigo::Key::Layout('8'),
-                35 => enigo::Key::Layout('9'),
-                36 => enigo::Key::Layout(' '),
-                37 => enigo::Key::Return,
-                38 => enigo::Key::Shift,
-                39 => enigo::Key::Control,
-                40 => enigo::Key::DownArrow,
-                41 => enigo::Key::LeftArrow,
-                42 => enigo::Key::RightArrow,
-                43 => enigo::Key::UpArrow,
-                44 => enigo::Key::Layout('\''),
-                45 => enigo::Key::Layout('`'),
-                46 => enigo::Key::Layout('\\'),
-                47 => enigo::Key::Layout(','),
-                48 => enigo::Key::Layout('='),
-                49 => enigo::Key::Layout('['),
-                50 => enigo::Key::Layout('-'),
-                51 => enigo::Key::Layout('.'),
-                52 => enigo::Key::Layout(']'),
-                53 => enigo::Key::Layout(';'),
-                54 => enigo::Key::Layout('/'),
-                55 => enigo::Key::Backspace,
-                56 => enigo::Key::Delete,
-                57 => enigo::Key::End,
-                58 => enigo::Key::Home,
-                59 => enigo::Key::PageDown,
-                60 => enigo::Key::PageUp,
-                61 => enigo::Key::Tab,
-                62 => enigo::Key::CapsLock,
-                63 => enigo::Key::Alt,
-                _ => enigo::Key::Escape, // has to be here because usize, shouldn't ever be reached
-            });
+            held_keys.push(KEYS[i]);
         };
     }
 
     held_keys
 }
+
+/// A key on the server's keyboard, independent of the output backend used to press it.
+/// The left and right variants of modifier keys share a flag, so they are reproduced as the left key.
+/// Keys after `LeftAlt` have no flag, but can be pressed by remapping other keys to them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+    M,
+    N,
+    O,
+    P,
+    Q,
+    R,
+    S,
+    T,
+    U,
+    V,
+    W,
+    X,
+    Y,
+    Z,
+    Key0,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Space,
+    Enter,
+    LeftShift,
+    LeftCtrl,
+    Down,
+    Left,
+    Right,
+    Up,
+    Apostrophe,
+    Backquote,
+    Backslash,
+    Comma,
+    Equal,
+    LeftBracket,
+    Minus,
+    Period,
+    RightBracket,
+    Semicolon,
+    Slash,
+    Backspace,
+    Delete,
+    End,
+    Home,
+    PageDown,
+    PageUp,
+    Tab,
+    CapsLock,
+    LeftAlt,
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    RightShift,
+    RightCtrl,
+    RightAlt,
+    LeftMeta,
+    RightMeta,
+    /// The extra key beside the left shift key on ISO keyboards, which types `<` on most European layouts.
+    IntlBackslash,
+}
+
+impl Key {
+    /// Parse a key from its name, ignoring case.
+    /// Accepts the names of the variants of `Key` as well as common aliases, such as `Ctrl` and `Cmd`.
+    pub fn from_name(name: &str) -> Option<Key> {
+        let alias = match name.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => Some(Key::LeftCtrl),
+            "shift" => Some(Key::LeftShift),
+            "alt" | "option" => Some(Key::LeftAlt),
+            "meta" | "cmd" | "command" | "super" | "win" | "windows" => Some(Key::LeftMeta),
+            "esc" => Some(Key::Escape),
+            "return" => Some(Key::Enter),
+            _ => None,
+        };
+
+        alias.or_else(|| all_keys().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name)))
+    }
+
+    /// The USB HID usage ID of the key, which identifies its physical position on the keyboard.
+    pub fn usage(self) -> u8 {
+        match self {
+            Key::A => 0x04,
+            Key::B => 0x05,
+            Key::C => 0x06,
+            Key::D => 0x07,
+            Key::E => 0x08,
+            Key::F => 0x09,
+            Key::G => 0x0a,
+            Key::H => 0x0b,
+            Key::I => 0x0c,
+            Key::J => 0x0d,
+            Key::K => 0x0e,
+            Key::L => 0x0f,
+            Key::M => 0x10,
+            Key::N => 0x11,
+            Key::O => 0x12,
+            Key::P => 0x13,
+            Key::Q => 0x14,
+            Key::R => 0x15,
+            Key::S => 0x16,
+            Key::T => 0x17,
+            Key::U => 0x18,
+            Key::V => 0x19,
+            Key::W => 0x1a,
+            Key::X => 0x1b,
+            Key::Y => 0x1c,
+            Key::Z => 0x1d,
+            Key::Key0 => 0x27,
+            Key::Key1 => 0x1e,
+            Key::Key2 => 0x1f,
+            Key::Key3 => 0x20,
+            Key::Key4 => 0x21,
+            Key::Key5 => 0x22,
+            Key::Key6 => 0x23,
+            Key::Key7 => 0x24,
+            Key::Key8 => 0x25,
+            Key::Key9 => 0x26,
+            Key::Space => 0x2c,
+            Key::Enter => 0x28,
+            Key::LeftShift => 0xe1,
+            Key::LeftCtrl => 0xe0,
+            Key::Down => 0x51,
+            Key::Left => 0x50,
+            Key::Right => 0x4f,
+            Key::Up => 0x52,
+            Key::Apostrophe => 0x34,
+            Key::Backquote => 0x35,
+            Key::Backslash => 0x31,
+            Key::Comma => 0x36,
+            Key::Equal => 0x2e,
+            Key::LeftBracket => 0x2f,
+            Key::Minus => 0x2d,
+            Key::Period => 0x37,
+            Key::RightBracket => 0x30,
+            Key::Semicolon => 0x33,
+            Key::Slash => 0x38,
+            Key::Backspace => 0x2a,
+            Key::Delete => 0x4c,
+            Key::End => 0x4d,
+            Key::Home => 0x4a,
+            Key::PageDown => 0x4e,
+            Key::PageUp => 0x4b,
+            Key::Tab => 0x2b,
+            Key::CapsLock => 0x39,
+            Key::LeftAlt => 0xe2,
+            Key::Escape => 0x29,
+            Key::F1 => 0x3a,
+            Key::F2 => 0x3b,
+            Key::F3 => 0x3c,
+            Key::F4 => 0x3d,
+            Key::F5 => 0x3e,
+            Key::F6 => 0x3f,
+            Key::F7 => 0x40,
+            Key::F8 => 0x41,
+            Key::F9 => 0x42,
+            Key::F10 => 0x43,
+            Key::F11 => 0x44,
+            Key::F12 => 0x45,
+            Key::RightShift => 0xe5,
+            Key::RightCtrl => 0xe4,
+            Key::RightAlt => 0xe6,
+            Key::LeftMeta => 0xe3,
+            Key::RightMeta => 0xe7,
+            Key::IntlBackslash => 0x64,
+        }
+    }
+
+    /// Find the key with the given USB HID usage ID.
+    pub fn from_usage(usage: u8) -> Option<Key> {
+        all_keys().find(|key| key.usage() == usage)
+    }
+}
+
+/// Every key, including those without a flag.
+pub fn all_keys() -> impl Iterator<Item = Key> {
+    KEYS.iter().chain(EXTRA_KEYS.iter()).copied()
+}
+
+/// Every key in the order of its flag index.
+pub const KEYS: [Key; 64] = [
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+    Key::Key0,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+    Key::Space,
+    Key::Enter,
+    Key::LeftShift,
+    Key::LeftCtrl,
+    Key::Down,
+    Key::Left,
+    Key::Right,
+    Key::Up,
+    Key::Apostrophe,
+    Key::Backquote,
+    Key::Backslash,
+    Key::Comma,
+    Key::Equal,
+    Key::LeftBracket,
+    Key::Minus,
+    Key::Period,
+    Key::RightBracket,
+    Key::Semicolon,
+    Key::Slash,
+    Key::Backspace,
+    Key::Delete,
+    Key::End,
+    Key::Home,
+    Key::PageDown,
+    Key::PageUp,
+    Key::Tab,
+    Key::CapsLock,
+    Key::LeftAlt,
+];
+
+/// Every key without a flag.
+const EXTRA_KEYS: [Key; 19] = [
+    Key::Escape,
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+    Key::F10,
+    Key::F11,
+    Key::F12,
+    Key::RightShift,
+    Key::RightCtrl,
+    Key::RightAlt,
+    Key::LeftMeta,
+    Key::RightMeta,
+    Key::IntlBackslash,
+];
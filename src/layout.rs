@@ -0,0 +1,219 @@
+use crate::keyboard::Key;
+
+/// The keys which type characters, in the order their characters are listed in each layout.
+const CHARACTER_KEYS: [Key; 48] = [
+    Key::Backquote,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+    Key::Key0,
+    Key::Minus,
+    Key::Equal,
+    Key::Q,
+    Key::W,
+    Key::E,
+    Key::R,
+    Key::T,
+    Key::Y,
+    Key::U,
+    Key::I,
+    Key::O,
+    Key::P,
+    Key::LeftBracket,
+    Key::RightBracket,
+    Key::A,
+    Key::S,
+    Key::D,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::Semicolon,
+    Key::Apostrophe,
+    Key::Backslash,
+    Key::Z,
+    Key::X,
+    Key::C,
+    Key::V,
+    Key::B,
+    Key::N,
+    Key::M,
+    Key::Comma,
+    Key::Period,
+    Key::Slash,
+    Key::IntlBackslash,
+];
+
+/// A keyboard layout, describing the character each key types without any modifiers held.
+/// Keys are named after their position on a US keyboard, so `Key::Z` types `y` on a German keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    Us,
+    Gb,
+    De,
+    Fr,
+    Es,
+    It,
+    Se,
+}
+
+impl Layout {
+    /// Parse a layout from its name, which is the lowercase two letter country code.
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "us" => Some(Layout::Us),
+            "gb" | "uk" => Some(Layout::Gb),
+            "de" => Some(Layout::De),
+            "fr" => Some(Layout::Fr),
+            "es" => Some(Layout::Es),
+            "it" => Some(Layout::It),
+            "se" | "fi" => Some(Layout::Se),
+            _ => None,
+        }
+    }
+
+    /// The characters typed by `CHARACTER_KEYS` on this layout, with dead keys given as their accent.
+    fn characters(self) -> &'static str {
+        match self {
+            Layout::Us => "`1234567890-=qwertyuiop[]asdfghjkl;'\\zxcvbnm,./<",
+            Layout::Gb => "`1234567890-=qwertyuiop[]asdfghjkl;'#zxcvbnm,./\\",
+            Layout::De => "^1234567890ß´qwertzuiopü+asdfghjklöä#yxcvbnm,.-<",
+            Layout::Fr => "²&é\"'(-è_çà)=azertyuiop^$qsdfghjklmù*wxcvbn,;:!<",
+            Layout::Es => "º1234567890'¡qwertyuiop`+asdfghjklñ´çzxcvbnm,.-<",
+            Layout::It => "\\1234567890'ìqwertyuiopè+asdfghjklòàùzxcvbnm,.-<",
+            Layout::Se => "§1234567890+´qwertyuiopå¨asdfghjklöä'zxcvbnm,.-<",
+        }
+    }
+
+    /// The character typed by `key` on this layout, if it types one.
+    pub fn character(self, key: Key) -> Option<char> {
+        let index = CHARACTER_KEYS.iter().position(|k| *k == key)?;
+        self.characters().chars().nth(index)
+    }
+
+    /// The key which types `character` on this layout, if there is one.
+    pub fn key(self, character: char) -> Option<Key> {
+        let index = self.characters().chars().position(|c| c == character)?;
+        Some(CHARACTER_KEYS[index])
+    }
+}
+
+/// Translates keys pressed on a client with one layout into the keys which type the same characters
+/// on a server with another, leaving keys alone if they don't type a character or it can't be typed.
+///
+/// Keys are matched by the character they type without modifiers, so symbols typed with Shift or AltGr
+/// are still pressed by position, and type whatever the server's layout has there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Translation {
+    pub client: Layout,
+    pub target: Layout,
+}
+
+impl Translation {
+    pub fn apply(&self, keys: &[Key]) -> Vec<Key> {
+        keys.iter()
+            .map(|key| {
+                self.client
+                    .character(*key)
+                    .and_then(|character| self.target.key(character))
+                    .unwrap_or(*key)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_characters_by_position() {
+        assert_eq!(Layout::Us.character(Key::Z), Some('z'));
+        assert_eq!(Layout::De.character(Key::Z), Some('y'));
+        assert_eq!(Layout::Fr.character(Key::Key2), Some('é'));
+        assert_eq!(Layout::Us.character(Key::Enter), None);
+
+        assert_eq!(Layout::De.key('z'), Some(Key::Y));
+        assert_eq!(Layout::Fr.key('a'), Some(Key::Q));
+        assert_eq!(Layout::Us.key('ß'), None);
+    }
+
+    #[test]
+    fn every_layout_covers_every_character_key() {
+        for layout in [
+            Layout::Us,
+            Layout::Gb,
+            Layout::De,
+            Layout::Fr,
+            Layout::Es,
+            Layout::It,
+            Layout::Se,
+        ] {
+            assert_eq!(layout.characters().chars().count(), CHARACTER_KEYS.len());
+        }
+    }
+
+    #[test]
+    fn translates_characters_and_leaves_other_keys() {
+        let translation = Translation {
+            client: Layout::De,
+            target: Layout::Us,
+        };
+
+        // `y` on a German keyboard, a dead key with no US equivalent, and a key with no character
+        assert_eq!(
+            translation.apply(&[Key::Z, Key::Equal, Key::LeftShift]),
+            vec![Key::Y, Key::Equal, Key::LeftShift]
+        );
+    }
+
+    #[test]
+    fn translates_the_iso_key() {
+        let translation = Translation {
+            client: Layout::Gb,
+            target: Layout::Us,
+        };
+        assert_eq!(
+            translation.apply(&[Key::IntlBackslash]),
+            vec![Key::Backslash]
+        );
+
+        let translation = Translation {
+            client: Layout::Us,
+            target: Layout::De,
+        };
+        assert_eq!(
+            translation.apply(&[Key::IntlBackslash]),
+            vec![Key::IntlBackslash]
+        );
+    }
+
+    #[test]
+    fn shifted_symbols_are_pressed_by_position() {
+        let translation = Translation {
+            client: Layout::De,
+            target: Layout::Us,
+        };
+
+        // Shift+7 types `/` on a German keyboard, but `&` on a US one
+        assert_eq!(
+            translation.apply(&[Key::LeftShift, Key::Key7]),
+            vec![Key::LeftShift, Key::Key7]
+        );
+    }
+
+    #[test]
+    fn parses_layout_names_and_aliases() {
+        assert_eq!(Layout::from_name("uk"), Some(Layout::Gb));
+        assert_eq!(Layout::from_name("fi"), Some(Layout::Se));
+        assert_eq!(Layout::from_name("US"), None);
+    }
+}
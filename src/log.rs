@@ -0,0 +1,265 @@
+use std::{
+    fmt::{self, Display},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How important a log message is, from most to least important.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warning),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// The syslog priority of the level, which journald reads from a `<N>` prefix on each line.
+    fn priority(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warning => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+/// How log messages are written out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Plain messages for people to read, prefixed with their priority when going to the journal.
+    Text,
+    /// One JSON object per line, including every field of the event.
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The most detailed level to log for each module, parsed from `REMOUSE_LOG`.
+///
+/// This is a comma separated list of levels, each optionally prefixed by the module it applies
+/// to, such as `warn,server=debug`. The most specific matching entry is used, and modules
+/// without one log at the `info` level. A level of `off` turns logging off.
+struct Filter {
+    default: Option<Level>,
+    modules: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Filter {
+        let mut filter = Filter {
+            default: Some(Level::Info),
+            modules: Vec::new(),
+        };
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (module, level) = match directive.split_once('=') {
+                Some((module, level)) => (Some(module.trim()), level.trim()),
+                None => (None, directive),
+            };
+            let level = match level {
+                "off" => None,
+                level => match Level::from_name(level) {
+                    Some(level) => Some(level),
+                    None => continue,
+                },
+            };
+
+            match module {
+                Some(module) => filter.modules.push((module.to_string(), level)),
+                None => filter.default = level,
+            }
+        }
+
+        // Check longer, more specific modules first
+        filter
+            .modules
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        filter
+    }
+
+    fn enabled(&self, module: &str, level: Level) -> bool {
+        let limit = self
+            .modules
+            .iter()
+            .find(|(prefix, _)| module == prefix || module.starts_with(&format!("{}::", prefix)))
+            .map_or(self.default, |(_, limit)| *limit);
+
+        limit.is_some_and(|limit| level <= limit)
+    }
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+/// Set how log messages are written. This should be called before anything is logged.
+pub fn init(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+fn filter() -> &'static Filter {
+    static FILTER: OnceLock<Filter> = OnceLock::new();
+    FILTER.get_or_init(|| Filter::parse(&std::env::var("REMOUSE_LOG").unwrap_or_default()))
+}
+
+/// Whether the output is connected to the systemd journal, which sets `JOURNAL_STREAM` for services.
+fn to_journal() -> bool {
+    static TO_JOURNAL: OnceLock<bool> = OnceLock::new();
+    *TO_JOURNAL.get_or_init(|| std::env::var_os("JOURNAL_STREAM").is_some())
+}
+
+/// Write a log message from `module` with the given fields, if the filter allows it.
+pub fn write(level: Level, module: &str, fields: &[(&str, &dyn Display)], message: fmt::Arguments) {
+    // Module paths start with the crate name, which is the same everywhere
+    let module = module.split_once("::").map_or("main", |(_, module)| module);
+
+    if !filter().enabled(module, level) {
+        return;
+    }
+
+    match FORMAT.get().copied().unwrap_or(Format::Text) {
+        Format::Text if to_journal() => println!("<{}>{}", level.priority(), message),
+        Format::Text if level == Level::Info => println!("{}", message),
+        Format::Text => println!("{}: {}", level.name(), message),
+        Format::Json => {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+
+            let mut line = format!(
+                "{{\"time\":{:.3},\"level\":\"{}\",\"module\":{},\"message\":{}",
+                time,
+                level.name(),
+                json_string(module),
+                json_string(&message.to_string())
+            );
+            for (name, value) in fields {
+                line += &format!(",{}:{}", json_string(name), json_string(&value.to_string()));
+            }
+            line.push('}');
+
+            println!("{}", line);
+        }
+    }
+}
+
+/// Quote and escape a string for JSON.
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// Log a message at the given level, optionally with fields before a `;`:
+///
+/// ```
+/// log!(Level::Info, event = "connect", peer = ip; "receiving input from {}", ip);
+/// ```
+///
+/// Fields are only written out in the JSON format.
+macro_rules! log {
+    ($level:expr, $($name:ident = $value:expr),+; $($arg:tt)+) => {
+        $crate::log::write(
+            $level,
+            module_path!(),
+            &[$((stringify!($name), &$value as &dyn std::fmt::Display)),+],
+            format_args!($($arg)+),
+        )
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::write($level, module_path!(), &[], format_args!($($arg)+))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log!($crate::log::Level::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!($crate::log::Level::Warning, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\"\\"), "\"say \\\"hi\\\"\\\\\"");
+        assert_eq!(json_string("a\nb\tc\r"), "\"a\\nb\\tc\\r\"");
+        assert_eq!(json_string("\u{1b}[0m"), "\"\\u001b[0m\"");
+        assert_eq!(json_string("café"), "\"café\"");
+    }
+
+    #[test]
+    fn filters_by_the_most_specific_module() {
+        let filter = Filter::parse("warn, server=debug, server::pairing=off, bogus=loud");
+
+        assert!(filter.enabled("client", Level::Warning));
+        assert!(!filter.enabled("client", Level::Info));
+        assert!(filter.enabled("server", Level::Debug));
+        assert!(filter.enabled("server::session", Level::Debug));
+        assert!(!filter.enabled("server::pairing", Level::Error));
+        assert!(!filter.enabled("serverless", Level::Info));
+        assert!(filter.enabled("bogus", Level::Warning));
+    }
+
+    #[test]
+    fn defaults_to_info() {
+        let filter = Filter::parse("");
+
+        assert!(filter.enabled("main", Level::Info));
+        assert!(!filter.enabled("main", Level::Debug));
+        assert!(!Filter::parse("off").enabled("main", Level::Error));
+    }
+}
@@ -1,40 +1,548 @@
+#[macro_use]
+mod log;
+
+mod access;
+mod address;
 mod client;
+mod config;
+mod control;
+mod daemon;
+mod discovery;
+#[cfg(target_os = "linux")]
+mod evdev;
+mod hooks;
+mod hotkey;
+mod input;
 mod keyboard;
+mod layout;
+mod mdns;
+mod metrics;
+mod output;
+mod pairing;
+mod pointer;
+mod ratelimit;
+mod remap;
 mod server;
+mod session;
+mod signals;
+#[cfg(target_os = "linux")]
+mod uinput;
+
+use access::AccessList;
+use config::Config;
+use control::ControlSocket;
+use daemon::PidFile;
+use hooks::Hooks;
+use hotkey::Hotkey;
+use input::Capture;
+use layout::{Layout, Translation};
+use log::Format;
+use metrics::Metrics;
+use output::Backend;
+use pairing::TrustStore;
+use pointer::{Acceleration, Region};
+use ratelimit::Limits;
+use remap::Remap;
+use server::{Listeners, Settings};
+use session::Policy;
+use signals::Signal;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 fn show_help_text() {
-    println!("Usage:\n  remouse connect [IP]\n  remouse serve");
+    println!(
+        "Usage:\n  remouse connect ADDRESS|--name HOST [--source-port PORT] [--layout LAYOUT] [--no-override-movement]\n                  [--headless] [--capture window|evdev|stdin] [--grab] [--release-hotkey HOTKEY]\n                  [--pause-hotkey HOTKEY] [--log-format text|json]\n  remouse serve [--config FILE] [--bind IP] [--port PORT] [--allow RANGE]... [--deny RANGE]...\n                [--policy exclusive|last-active|merged] [--handover MS] [--pairing] [--trust-store FILE]\n                [--sensitivity N] [--acceleration flat|linear|curve] [--acceleration-factor N] [--curve POINTS]\n                [--confine X,Y,W,H] [--monitor N (needs xrandr)]\n                [--packet-rate N] [--event-rate N] [--handshake-cooldown MS]\n                [--on-connect COMMAND] [--on-disconnect COMMAND] [--no-discovery] [--no-mdns]\n                [--remap PROFILE] [--injection physical|layout] [--client-layout LAYOUT] [--target-layout LAYOUT]\n                [--backend enigo|uinput] [--dry-run] [--daemon] [--pid-file FILE] [--log-file FILE]\n                [--metrics-port PORT] [--control-socket FILE] [--log-format text|json]\n  remouse discover\n  remouse ctl [--socket FILE] sessions|kick ID|pause|resume|release|held|unlock-pairing"
+    );
+}
+
+/// Get the value following the option `name`, if it was given.
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.get(index + 1).map(|value| value.as_str())
 }
 
-fn connect(args: &Vec<String>) {
-    println!("connecting...");
+/// Get the values following every occurrence of the repeatable option `name`.
+fn options<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
 
-    let ip = &args[2];
-    let client_option = client::init(ip.clone());
+fn connect(args: &[String]) {
+    let address = match option(args, "--name") {
+        Some(name) => match find_server(name) {
+            Ok(address) => address,
+            Err(e) => return error!("{}", e),
+        },
+        None => match address::parse(&args[2], address::DEFAULT_PORT) {
+            Some(address) => address,
+            // Fall back to multicast DNS for `.local` names and service instances
+            None => match mdns::resolve(&args[2], address::DEFAULT_PORT) {
+                Ok(Some(address)) => address,
+                Ok(None) => return error!("could not resolve address {}", args[2]),
+                Err(e) => return error!("could not resolve address {}: {}", args[2], e),
+            },
+        },
+    };
 
-    if let Some(mut client) = client_option {
-        println!("connected, streaming input");
-        let allow_movement = args.len() == 4 && args[3] == "--no-override-movement";
-        client::run(&mut client, !allow_movement);
-    } else {
-        println!("connection failed, are you on the same version?");
+    let source_port = match option(args, "--source-port").map(|port| port.parse()) {
+        Some(Ok(port)) => port,
+        Some(Err(_)) => return show_help_text(),
+        None => 0,
+    };
+
+    let layout = match option(args, "--layout") {
+        Some(name) => match Layout::from_name(name) {
+            Some(layout) => layout,
+            None => return error!("unknown layout {}", name),
+        },
+        None => Layout::Us,
+    };
+
+    let release = option(args, "--release-hotkey").unwrap_or(hotkey::DEFAULT_RELEASE);
+    let release = match Hotkey::parse(release) {
+        Ok(release) => release,
+        Err(e) => return error!("invalid release hotkey: {}", e),
+    };
+
+    let pause = option(args, "--pause-hotkey").unwrap_or(hotkey::DEFAULT_PAUSE);
+    let pause = match Hotkey::parse(pause) {
+        Ok(pause) => pause,
+        Err(e) => return error!("invalid pause hotkey: {}", e),
+    };
+
+    let headless = args.iter().any(|arg| arg == "--headless");
+    let capture = match option(args, "--capture") {
+        Some(name) => match Capture::from_name(name) {
+            Some(capture) => capture,
+            None => return error!("unknown capture backend {}", name),
+        },
+        None if headless => Capture::headless(),
+        #[cfg(windows)]
+        None => Capture::Window,
+        #[cfg(not(windows))]
+        None => Capture::headless(),
+    };
+    #[cfg(windows)]
+    if headless && capture == Capture::Window {
+        return error!("the window capture backend can't be used with --headless");
+    }
+
+    let allow_movement = args.iter().any(|arg| arg == "--no-override-movement");
+    let grab = args.iter().any(|arg| arg == "--grab");
+    let source = match capture.create(layout, !allow_movement, grab) {
+        Ok(source) => source,
+        Err(e) => return error!(error = e; "failed to capture input: {}", e),
+    };
+
+    info!(peer = address; "connecting...");
+    match client::init(address, source_port, source, layout, release, pause) {
+        Ok(Some(mut client)) => {
+            info!(event = "connect", peer = address; "connected, streaming input");
+            match client::run(&mut client) {
+                client::Stop::Released => info!(event = "disconnect"; "released, disconnected"),
+                client::Stop::Closed => info!(event = "disconnect"; "input closed, disconnected"),
+                client::Stop::Rejected => {
+                    warn!(event = "disconnect", peer = address; "the server closed the connection")
+                }
+            }
+        }
+        Ok(None) => error!(
+            event = "rejected", peer = address;
+            "connection failed, are you on the same version?"
+        ),
+        Err(e) => {
+            error!(event = "connect_error", peer = address, error = e; "connection failed: {}", e)
+        }
+    }
+}
+
+/// Find the address of the server on the local network with the given hostname, ignoring case.
+fn find_server(name: &str) -> Result<SocketAddr, String> {
+    let servers =
+        discovery::discover().map_err(|e| format!("failed to search for servers: {}", e))?;
+
+    servers
+        .iter()
+        .find(|server| server.name.eq_ignore_ascii_case(name))
+        .map(|server| server.address)
+        .ok_or(format!(
+            "no server named {} found on the local network",
+            name
+        ))
+}
+
+/// List the servers on the local network.
+fn discover() {
+    let servers = match discovery::discover() {
+        Ok(servers) => servers,
+        Err(e) => return error!("failed to search for servers: {}", e),
+    };
+
+    if servers.is_empty() {
+        return println!("no servers found");
+    }
+
+    for server in servers {
+        println!(
+            "{}\t{}\tversion {}{}",
+            server.name,
+            server.address,
+            server.version,
+            match server.busy {
+                true => "\tbusy",
+                false => "",
+            }
+        );
+    }
+}
+
+/// Load the config file given with `--config`, or an empty config if there isn't one.
+fn load_config(args: &[String]) -> Result<Config, String> {
+    match option(args, "--config") {
+        Some(path) => config::load(path).map_err(|e| format!("failed to load config: {}", e)),
+        None => Ok(Config::empty()),
+    }
+}
+
+/// Load the server settings which can be given both on the command line and in the config file.
+/// Options on the command line take priority.
+fn load_settings(args: &[String], config: &Config, backend: Backend) -> Result<Settings, String> {
+    let access = AccessList::new(&options(args, "--allow"), &options(args, "--deny"), config)?;
+
+    let handover = match option(args, "--handover").or(config.value("", "handover")) {
+        Some(ms) => Duration::from_millis(
+            ms.parse()
+                .map_err(|_| format!("invalid handover delay {}", ms))?,
+        ),
+        None => Duration::from_millis(500),
+    };
+    let policy = match option(args, "--policy").or(config.value("", "policy")) {
+        Some(name) => {
+            Policy::from_name(name, handover).ok_or(format!("unknown policy {}", name))?
+        }
+        None => Policy::Exclusive,
+    };
+
+    let acceleration = Acceleration::parse(
+        option(args, "--sensitivity").or(config.value("", "sensitivity")),
+        option(args, "--acceleration").or(config.value("", "acceleration")),
+        option(args, "--acceleration-factor").or(config.value("", "acceleration_factor")),
+        option(args, "--curve").or(config.value("", "curve")),
+    )?;
+
+    let confine = match (
+        option(args, "--confine").or(config.value("", "confine")),
+        option(args, "--monitor").or(config.value("", "monitor")),
+    ) {
+        (Some(region), _) => Some(Region::parse(region)?),
+        (None, Some(index)) => Some(Region::monitor(
+            index
+                .parse()
+                .map_err(|_| format!("invalid monitor {}", index))?,
+        )?),
+        (None, None) => None,
+    };
+
+    // The uinput backend moves the pointer with a device sized to the desktop, which xrandr reports
+    #[cfg(target_os = "linux")]
+    if confine.is_some() && backend == Backend::Uinput {
+        Region::desktop().map_err(|e| {
+            format!(
+                "confining the pointer with the uinput backend needs xrandr to find the desktop size: {}",
+                e
+            )
+        })?;
     }
+
+    let limits = Limits::parse(
+        option(args, "--packet-rate").or(config.value("", "packet_rate")),
+        option(args, "--event-rate").or(config.value("", "event_rate")),
+        option(args, "--handshake-cooldown").or(config.value("", "handshake_cooldown")),
+    )?;
+
+    let hooks = Hooks {
+        on_connect: option(args, "--on-connect")
+            .or(config.value("", "on_connect"))
+            .map(String::from),
+        on_disconnect: option(args, "--on-disconnect")
+            .or(config.value("", "on_disconnect"))
+            .map(String::from),
+    };
+
+    let remap = match option(args, "--remap").or(config.value("", "remap")) {
+        Some(profile) => Remap::load(config, profile)?,
+        None => Remap::identity(),
+    };
+
+    let layout = |name: Option<&str>| match name {
+        Some(name) => Layout::from_name(name).ok_or(format!("unknown layout {}", name)),
+        None => Ok(Layout::Us),
+    };
+    // enigo types characters, so it can only inject by layout
+    let injection = option(args, "--injection")
+        .or(config.value("", "injection"))
+        .unwrap_or(match backend {
+            Backend::Enigo => "layout",
+            _ => "physical",
+        });
+    let translation = match injection {
+        "physical" if backend == Backend::Enigo => {
+            return Err(String::from(
+                "the enigo backend types characters rather than pressing physical keys, use `--injection layout` or another backend",
+            ))
+        }
+        "physical" => None,
+        "layout" => Some(Translation {
+            client: layout(option(args, "--client-layout").or(config.value("", "client_layout")))?,
+            target: layout(option(args, "--target-layout").or(config.value("", "target_layout")))?,
+        }),
+        injection => return Err(format!("unknown injection mode {}", injection)),
+    };
+
+    Ok(Settings {
+        access,
+        policy,
+        acceleration,
+        confine,
+        remap,
+        limits,
+        hooks,
+        translation,
+    })
 }
 
-fn serve() {
-    println!("starting server...");
-    let mut server = server::init();
-    println!("waiting for client...");
-    server::run(&mut server);
+fn serve(args: &[String]) {
+    let backend = match option(args, "--backend") {
+        Some(name) => match Backend::from_name(name) {
+            Some(backend) => backend,
+            None => return show_help_text(),
+        },
+        None => Backend::Enigo,
+    };
+    let backend = match args.iter().any(|arg| arg == "--dry-run") {
+        true => Backend::DryRun,
+        false => backend,
+    };
+
+    let port = match option(args, "--port").map(|port| port.parse()) {
+        Some(Ok(port)) => port,
+        Some(Err(_)) => return show_help_text(),
+        None => address::DEFAULT_PORT,
+    };
+    let bind_address = match option(args, "--bind") {
+        Some(ip) => match address::parse(ip, port) {
+            Some(bind_address) => bind_address,
+            None => return error!("could not resolve address {}", ip),
+        },
+        None => address::default_bind_address(port),
+    };
+
+    let config = match load_config(args) {
+        Ok(config) => config,
+        Err(e) => return error!("{}", e),
+    };
+    let settings = match load_settings(args, &config, backend) {
+        Ok(settings) => settings,
+        Err(e) => return error!("{}", e),
+    };
+    let pairing =
+        args.iter().any(|arg| arg == "--pairing") || config.value("", "pairing") == Some("true");
+    let trust_store = match pairing {
+        true => {
+            let path = match option(args, "--trust-store").or(config.value("", "trust_store")) {
+                Some(path) => PathBuf::from(path),
+                None => config::data_dir().join("trusted_clients"),
+            };
+            match TrustStore::load(path) {
+                Ok(trust_store) => Some(trust_store),
+                Err(e) => return error!("failed to load trust store: {}", e),
+            }
+        }
+        false => None,
+    };
+
+    let metrics = Arc::new(Metrics::default());
+    let metrics_listener = match option(args, "--metrics-port").or(config.value("", "metrics_port"))
+    {
+        Some(port) => match port.parse() {
+            Ok(port) => match metrics::bind(port) {
+                Ok(listener) => Some(listener),
+                Err(e) => return error!("failed to start metrics endpoint: {}", e),
+            },
+            Err(_) => return error!("invalid metrics port {}", port),
+        },
+        None => None,
+    };
+
+    let control_path = option(args, "--control-socket")
+        .or(config.value("", "control_socket"))
+        .map_or_else(control::default_path, PathBuf::from);
+    let control = match ControlSocket::bind(control_path) {
+        Ok(control) => Some(control),
+        Err(e) => {
+            warn!(
+                "failed to create control socket, `remouse ctl` won't work: {}",
+                e
+            );
+            None
+        }
+    };
+
+    // Clients on the network couldn't connect to a server which only listens on loopback
+    let discovery = match args.iter().any(|arg| arg == "--no-discovery")
+        || config.value("", "discovery") == Some("false")
+        || bind_address.ip().is_loopback()
+    {
+        true => None,
+        false => match discovery::bind() {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                warn!("failed to listen for discovery requests, `remouse discover` won't find this server: {}", e);
+                None
+            }
+        },
+    };
+
+    let mdns = match args.iter().any(|arg| arg == "--no-mdns")
+        || config.value("", "mdns") == Some("false")
+        || bind_address.ip().is_loopback()
+    {
+        true => None,
+        false => {
+            let mut capabilities = vec!["physical-keys"];
+            if trust_store.is_some() {
+                capabilities.push("pairing");
+            }
+            let txt = vec![
+                format!("version={}", env!("CARGO_PKG_VERSION")),
+                format!("caps={}", capabilities.join(",")),
+            ];
+
+            let ip = Some(bind_address.ip()).filter(|ip| !ip.is_unspecified());
+            match mdns::Responder::new(ip, bind_address.port(), txt) {
+                Ok(responder) => Some(responder),
+                Err(e) => {
+                    warn!(
+                        "failed to start mDNS responder, the server won't be advertised: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        }
+    };
+
+    info!("starting server on {}...", bind_address);
+    let mut server = match server::init(
+        backend,
+        bind_address,
+        settings,
+        trust_store,
+        Arc::clone(&metrics),
+        Listeners {
+            control,
+            discovery,
+            mdns,
+        },
+    ) {
+        Ok(server) => server,
+        Err(e) => return error!("failed to start server: {}", e),
+    };
+
+    if args.iter().any(|arg| arg == "--daemon") {
+        if let Err(e) = daemon::daemonize(option(args, "--log-file").map(Path::new)) {
+            return error!("failed to start daemon: {}", e);
+        }
+    }
+    let _pid_file = match option(args, "--pid-file").map(|path| PidFile::create(path.into())) {
+        Some(Ok(pid_file)) => Some(pid_file),
+        Some(Err(e)) => return error!("failed to write PID file: {}", e),
+        None => None,
+    };
+    if let Some(listener) = metrics_listener {
+        metrics::serve(listener, metrics);
+    }
+
+    signals::install();
+    daemon::notify("READY=1");
+    info!("waiting for client...");
+
+    loop {
+        match server::run(&mut server) {
+            Signal::Hangup => {
+                daemon::notify("RELOADING=1");
+                info!("reloading settings");
+                match load_config(args).and_then(|config| load_settings(args, &config, backend)) {
+                    Ok(settings) => server::reload(&mut server, settings),
+                    Err(e) => error!("failed to reload settings, keeping the old ones: {}", e),
+                }
+                daemon::notify("READY=1");
+            }
+            signal => {
+                info!("received {:?} signal, shutting down", signal);
+                daemon::notify("STOPPING=1");
+                break;
+            }
+        }
+    }
+}
+
+/// Send a command to the running server over its control socket and print the response.
+fn ctl(args: &[String]) {
+    let path = match option(args, "--socket") {
+        Some(path) => PathBuf::from(path),
+        None => control::default_path(),
+    };
+
+    let mut words: Vec<&str> = Vec::new();
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--socket" | "--log-format" => {
+                rest.next();
+            }
+            word => words.push(word),
+        }
+    }
+
+    if let Err(e) = control::Command::parse(&words) {
+        error!("{}", e);
+        return show_help_text();
+    }
+
+    match control::send(&path, &words) {
+        Ok(response) => print!("{}", response),
+        Err(e) => error!(
+            "failed to reach the server at {}, is it running? {}",
+            path.display(),
+            e
+        ),
+    }
 }
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
+    match option(&args, "--log-format").map(Format::from_name) {
+        Some(Some(format)) => log::init(format),
+        Some(None) => return show_help_text(),
+        None => (),
+    }
+
     if args.len() >= 3 && args[1] == "connect" {
         connect(&args);
-    } else if args.len() == 2 && args[1] == "serve" {
-        serve();
+    } else if args.len() >= 2 && args[1] == "serve" {
+        serve(&args);
+    } else if args.len() >= 2 && args[1] == "discover" {
+        discover();
+    } else if args.len() >= 2 && args[1] == "ctl" {
+        ctl(&args);
     } else {
         return show_help_text();
     }
@@ -0,0 +1,642 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+/// The multicast group and port mDNS queries and responses are sent to.
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// The DNS-SD service type remouse servers advertise themselves under.
+pub const SERVICE_TYPE: &str = "_remouse._udp.local";
+
+/// The name DNS-SD browsers query to list every service type on the network.
+const SERVICE_TYPE_ENUMERATION: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the class of records which only this server answers for, so caches replace old copies.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// How long other machines may cache the records.
+const TTL: u32 = 120;
+
+/// The longest TTL which may be given in a reply to a simple resolver, which can't be told when records change.
+const LEGACY_TTL: u32 = 10;
+
+/// How long to wait for an answer when resolving a name.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A DNS resource record, with the data of the types remouse uses decoded.
+struct Record {
+    name: String,
+    data: Data,
+}
+
+enum Data {
+    A(Ipv4Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+impl Data {
+    fn record_type(&self) -> u16 {
+        match self {
+            Data::A(_) => TYPE_A,
+            Data::Ptr(_) => TYPE_PTR,
+            Data::Txt(_) => TYPE_TXT,
+            Data::Srv { .. } => TYPE_SRV,
+            Data::Other => 0,
+        }
+    }
+}
+
+/// Advertises the server as a `_remouse._udp` DNS-SD service over multicast DNS.
+pub struct Responder {
+    socket: UdpSocket,
+    /// The full name of the service instance, such as `myhost._remouse._udp.local`.
+    instance: String,
+    /// The name of this machine, such as `myhost.local`, or `myhost-remouse.local` if the server is bound to one address.
+    host: String,
+    /// The address the server is bound to, if it only listens on one.
+    ip: Option<Ipv4Addr>,
+    /// Whether this server answers for the address of `host`, rather than a system responder such as Avahi.
+    owns_host: bool,
+    port: u16,
+    txt: Vec<String>,
+}
+
+impl Responder {
+    /// Start answering mDNS queries for the server listening on `port`, named after this machine,
+    /// with `txt` as the `key=value` entries of its TXT record. The service is announced straight away.
+    ///
+    /// If another responder already answers for the machine's hostname, it is left to answer for
+    /// the address, as two responders claiming the same name would each see the other as a conflict.
+    /// If the server is bound to a specific `ip`, the service points at a `<hostname>-remouse.local`
+    /// name with only that address instead, as the machine's hostname may resolve to its other addresses.
+    pub fn new(ip: Option<IpAddr>, port: u16, txt: Vec<String>) -> io::Result<Responder> {
+        let ip = match ip.map(crate::address::canonical_ip) {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            Some(IpAddr::V6(ip)) => {
+                return Err(io::Error::other(format!(
+                    "only IPv4 addresses can be advertised, but the server is bound to {}",
+                    ip
+                )))
+            }
+            None => None,
+        };
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        // Other mDNS responders such as Avahi need to share the port too
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+        socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.set_nonblocking(true)?;
+
+        // Dots would split the name into several labels
+        let name = crate::discovery::hostname().replace('.', "-");
+        let host = match ip {
+            Some(_) => format!("{}-remouse.local", name),
+            None => format!("{}.local", name),
+        };
+
+        // Asking for the address before answering for it is the check RFC 6762 describes as probing
+        let owns_host = query(&host, TYPE_A)?.is_empty();
+        if !owns_host {
+            debug!(host = host; "another mDNS responder answers for {}, leaving the address to it", host);
+        }
+
+        let responder = Responder {
+            socket: socket.into(),
+            instance: format!("{}.{}", name, SERVICE_TYPE),
+            host,
+            ip,
+            owns_host,
+            port,
+            txt,
+        };
+
+        responder.announce(TTL);
+        Ok(responder)
+    }
+
+    /// Answer every waiting query about this server.
+    pub fn answer(&self) {
+        let mut buf = [0; 1500];
+
+        while let Ok((length, addr)) = self.socket.recv_from(&mut buf) {
+            let message = &buf[..length];
+            let questions = match parse_questions(message) {
+                Some(questions) => questions,
+                None => continue,
+            };
+
+            let local_ip = self.ip.or_else(|| local_ip_for(addr.ip()));
+            let mut answers: Vec<Record> = Vec::new();
+            let mut additional: Vec<Record> = Vec::new();
+
+            for (name, record_type) in &questions {
+                let is = |other: &str| name.eq_ignore_ascii_case(other);
+                let wants = |wanted: u16| *record_type == wanted || *record_type == TYPE_ANY;
+
+                if is(SERVICE_TYPE) && wants(TYPE_PTR) {
+                    answers.push(self.ptr());
+                    additional.extend(self.service_records());
+                    additional.extend(self.address(local_ip));
+                } else if is(SERVICE_TYPE_ENUMERATION) && wants(TYPE_PTR) {
+                    answers.push(Record {
+                        name: SERVICE_TYPE_ENUMERATION.to_string(),
+                        data: Data::Ptr(SERVICE_TYPE.to_string()),
+                    });
+                } else if is(&self.instance) && (wants(TYPE_SRV) || wants(TYPE_TXT)) {
+                    answers.extend(self.service_records());
+                    additional.extend(self.address(local_ip));
+                } else if is(&self.host) && wants(TYPE_A) {
+                    answers.extend(self.address(local_ip));
+                }
+            }
+
+            if answers.is_empty() {
+                continue;
+            }
+
+            // Queries from a port other than 5353 come from simple resolvers, which expect a direct reply
+            // echoing the query ID and questions, and don't listen to the multicast group
+            let result = match addr.port() {
+                MDNS_PORT => self.socket.send_to(
+                    &encode(0, &[], &answers, &additional, TTL),
+                    (MDNS_GROUP, MDNS_PORT),
+                ),
+                _ => {
+                    let id = u16::from_be_bytes([message[0], message[1]]);
+                    self.socket.send_to(
+                        &encode(id, &questions, &answers, &additional, LEGACY_TTL),
+                        addr,
+                    )
+                }
+            };
+
+            if let Err(e) = result {
+                warn!(event = "mdns_error", peer = addr, error = e; "failed to answer mDNS query: {}", e);
+            }
+        }
+    }
+
+    /// Send every record unprompted, so browsers see the service without asking.
+    /// A TTL of 0 tells them the service has gone away.
+    fn announce(&self, ttl: u32) {
+        let mut records = vec![self.ptr()];
+        records.extend(self.service_records());
+        records.extend(self.address(self.ip.or_else(|| local_ip_for(IpAddr::V4(MDNS_GROUP)))));
+
+        if let Err(e) = self
+            .socket
+            .send_to(&encode(0, &[], &records, &[], ttl), (MDNS_GROUP, MDNS_PORT))
+        {
+            warn!(event = "mdns_error", error = e; "failed to announce mDNS service: {}", e);
+        }
+    }
+
+    fn ptr(&self) -> Record {
+        Record {
+            name: SERVICE_TYPE.to_string(),
+            data: Data::Ptr(self.instance.clone()),
+        }
+    }
+
+    fn service_records(&self) -> Vec<Record> {
+        vec![
+            Record {
+                name: self.instance.clone(),
+                data: Data::Srv {
+                    port: self.port,
+                    target: self.host.clone(),
+                },
+            },
+            Record {
+                name: self.instance.clone(),
+                data: Data::Txt(self.txt.clone()),
+            },
+        ]
+    }
+
+    /// The address record for this machine, if this server answers for it.
+    fn address(&self, ip: Option<Ipv4Addr>) -> Option<Record> {
+        if !self.owns_host {
+            return None;
+        }
+
+        Some(Record {
+            name: self.host.clone(),
+            data: Data::A(ip?),
+        })
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        self.announce(0);
+    }
+}
+
+/// Resolve a `.local` hostname, or the name of a remouse service instance with or without the
+/// `._remouse._udp.local` suffix, over multicast DNS. Hostnames use `default_port`.
+pub fn resolve(name: &str, default_port: u16) -> io::Result<Option<SocketAddr>> {
+    let name = name.trim_end_matches('.');
+    let lower = name.to_ascii_lowercase();
+
+    let (host, port) = if lower.ends_with(SERVICE_TYPE) || !lower.ends_with(".local") {
+        let instance = match lower.ends_with(SERVICE_TYPE) {
+            true => name.to_string(),
+            false => format!("{}.{}", name, SERVICE_TYPE),
+        };
+
+        let records = query(&instance, TYPE_SRV)?;
+        let (port, target) = match records.iter().find_map(|record| match &record.data {
+            Data::Srv { port, target } if record.name.eq_ignore_ascii_case(&instance) => {
+                Some((*port, target.clone()))
+            }
+            _ => None,
+        }) {
+            Some(srv) => srv,
+            None => return Ok(None),
+        };
+
+        // Responders usually include the address along with the service
+        if let Some(ip) = find_address(&records, &target) {
+            return Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)));
+        }
+        (target, port)
+    } else {
+        (name.to_string(), default_port)
+    };
+
+    let records = query(&host, TYPE_A)?;
+    Ok(find_address(&records, &host).map(|ip| SocketAddr::new(IpAddr::V4(ip), port)))
+}
+
+fn find_address(records: &[Record], host: &str) -> Option<Ipv4Addr> {
+    records.iter().find_map(|record| match record.data {
+        Data::A(ip) if record.name.eq_ignore_ascii_case(host) => Some(ip),
+        _ => None,
+    })
+}
+
+/// Send a single question to the mDNS group and return the records in the first answer to it.
+fn query(name: &str, record_type: u16) -> io::Result<Vec<Record>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let id = std::process::id() as u16;
+    let question = (name.to_string(), record_type);
+    socket.send_to(
+        &encode(id, &[question], &[], &[], 0),
+        (MDNS_GROUP, MDNS_PORT),
+    )?;
+
+    let mut buf = [0; 1500];
+    let start = Instant::now();
+
+    while let Some(remaining) = RESOLVE_TIMEOUT.checked_sub(start.elapsed()) {
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+        let length = match socket.recv(&mut buf) {
+            Ok(length) => length,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(records) = parse_records(&buf[..length]) {
+            let answers = records.iter().any(|record| {
+                record.name.eq_ignore_ascii_case(name) && record.data.record_type() == record_type
+            });
+            if answers {
+                return Ok(records);
+            }
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+/// The IPv4 address this machine uses to reach `peer`, which is what it should advertise to it.
+fn local_ip_for(peer: IpAddr) -> Option<Ipv4Addr> {
+    let peer = match crate::address::canonical_ip(peer) {
+        IpAddr::V4(peer) => peer,
+        IpAddr::V6(_) => MDNS_GROUP,
+    };
+
+    // Connecting a UDP socket sends nothing, but picks the interface and address to use
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((peer, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+/// Encode a DNS message. Messages without answers are queries, those without questions are multicast
+/// responses, and those with both are replies to simple resolvers.
+fn encode(
+    id: u16,
+    questions: &[(String, u16)],
+    answers: &[Record],
+    additional: &[Record],
+    ttl: u32,
+) -> Vec<u8> {
+    let flags: u16 = match answers.is_empty() {
+        true => 0,
+        // A response with the authoritative answer bit set
+        false => 0x8400,
+    };
+
+    // Simple resolvers aren't mDNS caches, so replies to them must never have the cache flush bit set
+    let cache_flush = match questions.is_empty() {
+        true => CACHE_FLUSH,
+        false => 0,
+    };
+
+    let mut out = Vec::new();
+    for value in &[
+        id,
+        flags,
+        questions.len() as u16,
+        answers.len() as u16,
+        0,
+        additional.len() as u16,
+    ] {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+
+    for (name, record_type) in questions {
+        encode_name(&mut out, name);
+        out.extend_from_slice(&record_type.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+
+    for record in answers.iter().chain(additional) {
+        let (class, data) = match &record.data {
+            Data::A(ip) => (CLASS_IN | cache_flush, ip.octets().to_vec()),
+            // Several servers share the service type, so its records are never flushed
+            Data::Ptr(target) => {
+                let mut data = Vec::new();
+                encode_name(&mut data, target);
+                (CLASS_IN, data)
+            }
+            Data::Txt(entries) => {
+                let mut data = Vec::new();
+                for entry in entries {
+                    data.push(entry.len().min(255) as u8);
+                    data.extend_from_slice(&entry.as_bytes()[..entry.len().min(255)]);
+                }
+                (CLASS_IN | cache_flush, data)
+            }
+            Data::Srv { port, target } => {
+                let mut data = vec![0, 0, 0, 0];
+                data.extend_from_slice(&port.to_be_bytes());
+                encode_name(&mut data, target);
+                (CLASS_IN | cache_flush, data)
+            }
+            Data::Other => continue,
+        };
+
+        encode_name(&mut out, &record.name);
+        out.extend_from_slice(&record.data.record_type().to_be_bytes());
+        out.extend_from_slice(&class.to_be_bytes());
+        out.extend_from_slice(&ttl.to_be_bytes());
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&data);
+    }
+
+    out
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+/// Parse the questions in a query, ignoring responses.
+fn parse_questions(message: &[u8]) -> Option<Vec<(String, u16)>> {
+    let flags = u16::from_be_bytes([*message.get(2)?, *message.get(3)?]);
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+
+    let count = u16::from_be_bytes([*message.get(4)?, *message.get(5)?]);
+    let mut offset = 12;
+    let mut questions = Vec::new();
+
+    for _ in 0..count {
+        let name = decode_name(message, &mut offset)?;
+        let record_type = u16::from_be_bytes([*message.get(offset)?, *message.get(offset + 1)?]);
+        offset += 4;
+        questions.push((name, record_type));
+    }
+
+    Some(questions)
+}
+
+/// Parse every answer and additional record in a response.
+fn parse_records(message: &[u8]) -> Option<Vec<Record>> {
+    let count = |at: usize| {
+        Some(u16::from_be_bytes([
+            *message.get(at)?,
+            *message.get(at + 1)?,
+        ]))
+    };
+    let questions = count(4)?;
+    let records = count(6)? as usize + count(8)? as usize + count(10)? as usize;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        decode_name(message, &mut offset)?;
+        offset += 4;
+    }
+
+    let mut parsed = Vec::new();
+    for _ in 0..records {
+        let name = decode_name(message, &mut offset)?;
+        let record_type = count(offset)?;
+        let length = count(offset + 8)? as usize;
+        let start = offset + 10;
+        let data = message.get(start..start + length)?;
+        offset = start + length;
+
+        let data = match record_type {
+            TYPE_A if length == 4 => Data::A(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            TYPE_PTR => Data::Ptr(decode_name(message, &mut { start })?),
+            TYPE_SRV if length > 6 => Data::Srv {
+                port: u16::from_be_bytes([data[4], data[5]]),
+                target: decode_name(message, &mut { start + 6 })?,
+            },
+            _ => Data::Other,
+        };
+        parsed.push(Record { name, data });
+    }
+
+    Some(parsed)
+}
+
+/// Decode a possibly compressed name starting at `offset`, moving `offset` past it.
+fn decode_name(message: &[u8], offset: &mut usize) -> Option<String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut position = *offset;
+    let mut jumped = false;
+
+    // Bound the number of labels so a loop of pointers can't hang the server
+    for _ in 0..128 {
+        let length = *message.get(position)? as usize;
+
+        if length == 0 {
+            if !jumped {
+                *offset = position + 1;
+            }
+            return Some(labels.join("."));
+        }
+
+        if length & 0xc0 == 0xc0 {
+            let pointer = (length & 0x3f) << 8 | *message.get(position + 1)? as usize;
+            if !jumped {
+                *offset = position + 2;
+            }
+            jumped = true;
+            position = pointer;
+            continue;
+        }
+
+        let label = message.get(position + 1..position + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        position += 1 + length;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_plain_and_compressed_names() {
+        // A 12 byte header, then `vm.local`, then `_remouse.` followed by a pointer to `local`
+        let mut message = vec![0; 12];
+        encode_name(&mut message, "vm.local");
+        message.extend_from_slice(b"\x08_remouse\xc0\x0f");
+
+        let mut offset = 12;
+        assert_eq!(
+            decode_name(&message, &mut offset),
+            Some("vm.local".to_string())
+        );
+        assert_eq!(offset, 22);
+
+        assert_eq!(
+            decode_name(&message, &mut offset),
+            Some("_remouse.local".to_string())
+        );
+        assert_eq!(offset, message.len());
+    }
+
+    #[test]
+    fn rejects_pointer_loops_and_truncated_names() {
+        // A pointer to itself, and two pointers to each other
+        assert_eq!(decode_name(b"\xc0\x00", &mut 0), None);
+        assert_eq!(decode_name(b"\x01a\xc0\x04\x01b\xc0\x00", &mut 0), None);
+
+        assert_eq!(decode_name(b"\x05local", &mut 0), None);
+        assert_eq!(decode_name(b"\x05loc", &mut 0), None);
+        assert_eq!(decode_name(b"\xc0", &mut 0), None);
+        assert_eq!(decode_name(b"\xc0\x09", &mut 0), None);
+    }
+
+    #[test]
+    fn parses_encoded_questions_and_records() {
+        let query = encode(
+            0,
+            &[
+                (SERVICE_TYPE.to_string(), TYPE_PTR),
+                ("vm.local".to_string(), TYPE_A),
+            ],
+            &[],
+            &[],
+            TTL,
+        );
+        assert_eq!(
+            parse_questions(&query),
+            Some(vec![
+                (SERVICE_TYPE.to_string(), TYPE_PTR),
+                ("vm.local".to_string(), TYPE_A)
+            ])
+        );
+
+        let response = encode(
+            0,
+            &[],
+            &[Record {
+                name: SERVICE_TYPE.to_string(),
+                data: Data::Ptr(format!("vm.{}", SERVICE_TYPE)),
+            }],
+            &[
+                Record {
+                    name: format!("vm.{}", SERVICE_TYPE),
+                    data: Data::Srv {
+                        port: 5258,
+                        target: "vm.local".to_string(),
+                    },
+                },
+                Record {
+                    name: "vm.local".to_string(),
+                    data: Data::A(Ipv4Addr::new(192, 168, 1, 2)),
+                },
+            ],
+            TTL,
+        );
+        assert_eq!(parse_questions(&response), None);
+
+        let records = parse_records(&response).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(
+            matches!(&records[0].data, Data::Ptr(target) if *target == format!("vm.{}", SERVICE_TYPE))
+        );
+        assert!(
+            matches!(&records[1].data, Data::Srv { port: 5258, target } if target == "vm.local")
+        );
+        assert!(matches!(records[2].data, Data::A(ip) if ip == Ipv4Addr::new(192, 168, 1, 2)));
+    }
+
+    #[test]
+    fn replies_to_simple_resolvers_never_flush_caches() {
+        let record = || Record {
+            name: "vm.local".to_string(),
+            data: Data::A(Ipv4Addr::LOCALHOST),
+        };
+        // The class follows the header, the 10 byte name and the type
+        let class = |message: &[u8]| u16::from_be_bytes([message[24], message[25]]);
+
+        let multicast = encode(0, &[], &[record()], &[], TTL);
+        assert_eq!(class(&multicast), CLASS_IN | CACHE_FLUSH);
+
+        let question = ("vm.local".to_string(), TYPE_A);
+        let unicast = encode(1, &[question], &[record()], &[], LEGACY_TTL);
+        // Skip the echoed question
+        assert_eq!(class(&unicast[14..]), CLASS_IN);
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// The upper bounds of the packet processing time histogram's buckets, in seconds.
+const BUCKETS: [f64; 10] = [
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01,
+];
+
+/// The kinds of input event the server injects, counted separately.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    KeyDown,
+    KeyUp,
+    ButtonDown,
+    ButtonUp,
+    Scroll,
+    Move,
+}
+
+impl Event {
+    const ALL: [Event; 6] = [
+        Event::KeyDown,
+        Event::KeyUp,
+        Event::ButtonDown,
+        Event::ButtonUp,
+        Event::Scroll,
+        Event::Move,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Event::KeyDown => "key_down",
+            Event::KeyUp => "key_up",
+            Event::ButtonDown => "button_down",
+            Event::ButtonUp => "button_up",
+            Event::Scroll => "scroll",
+            Event::Move => "move",
+        }
+    }
+}
+
+/// The reasons the server drops packets to protect itself from floods.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    PacketRate,
+    EventRate,
+    HandshakeCooldown,
+}
+
+impl Limit {
+    const ALL: [Limit; 3] = [
+        Limit::PacketRate,
+        Limit::EventRate,
+        Limit::HandshakeCooldown,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Limit::PacketRate => "packet_rate",
+            Limit::EventRate => "event_rate",
+            Limit::HandshakeCooldown => "handshake_cooldown",
+        }
+    }
+}
+
+/// Counters and gauges describing the server's health, shared with the metrics endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    packets_received: AtomicU64,
+    packets_rejected: AtomicU64,
+    bytes_received: AtomicU64,
+    handshake_failures: AtomicU64,
+    active_sessions: AtomicU64,
+    held_keys: AtomicU64,
+    events: [AtomicU64; 6],
+    dropped: [AtomicU64; 3],
+    /// The number of packets which took at most each bucket's time, followed by the total count.
+    processing_buckets: [AtomicU64; 11],
+    processing_micros: AtomicU64,
+}
+
+impl Metrics {
+    /// Record a packet of `length` bytes which took `elapsed` to process.
+    pub fn packet(&self, length: usize, elapsed: Duration) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(length as u64, Ordering::Relaxed);
+
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.processing_buckets.iter().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.processing_buckets[BUCKETS.len()].fetch_add(1, Ordering::Relaxed);
+        self.processing_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Record a packet which was ignored because it was malformed or refused.
+    pub fn rejected(&self) {
+        self.packets_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a connection which was refused.
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a packet which was dropped for going over a limit.
+    pub fn dropped(&self, limit: Limit) {
+        self.dropped[limit as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record an injected input event.
+    pub fn injected(&self, event: Event) {
+        self.events[event as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_active_sessions(&self, count: usize) {
+        self.active_sessions.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_held_keys(&self, count: usize) {
+        self.held_keys.store(count as u64, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let get = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = write!(
+                out,
+                "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n",
+                name, help, kind, value
+            );
+        };
+        metric(
+            "remouse_packets_received_total",
+            "counter",
+            "Packets received from clients.",
+            get(&self.packets_received),
+        );
+        metric(
+            "remouse_packets_rejected_total",
+            "counter",
+            "Packets ignored because they were malformed or refused.",
+            get(&self.packets_rejected),
+        );
+        metric(
+            "remouse_received_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+            get(&self.bytes_received),
+        );
+        metric(
+            "remouse_handshake_failures_total",
+            "counter",
+            "Connections which were refused.",
+            get(&self.handshake_failures),
+        );
+        metric(
+            "remouse_active_sessions",
+            "gauge",
+            "Clients currently connected.",
+            get(&self.active_sessions),
+        );
+        metric(
+            "remouse_held_keys",
+            "gauge",
+            "Keys currently held down on this machine.",
+            get(&self.held_keys),
+        );
+
+        out += "# HELP remouse_injected_events_total Input events injected into this machine.\n";
+        out += "# TYPE remouse_injected_events_total counter\n";
+        for event in Event::ALL.iter() {
+            let _ = writeln!(
+                out,
+                "remouse_injected_events_total{{type=\"{}\"}} {}",
+                event.name(),
+                get(&self.events[*event as usize])
+            );
+        }
+
+        out +=
+            "# HELP remouse_packets_dropped_total Packets dropped for going over a rate limit.\n";
+        out += "# TYPE remouse_packets_dropped_total counter\n";
+        for limit in Limit::ALL.iter() {
+            let _ = writeln!(
+                out,
+                "remouse_packets_dropped_total{{limit=\"{}\"}} {}",
+                limit.name(),
+                get(&self.dropped[*limit as usize])
+            );
+        }
+
+        out += "# HELP remouse_packet_processing_seconds Time taken to handle each packet.\n";
+        out += "# TYPE remouse_packet_processing_seconds histogram\n";
+        for (bucket, bound) in self.processing_buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "remouse_packet_processing_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                get(bucket)
+            );
+        }
+        let count = get(&self.processing_buckets[BUCKETS.len()]);
+        let _ = writeln!(
+            out,
+            "remouse_packet_processing_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "remouse_packet_processing_seconds_sum {}",
+            get(&self.processing_micros) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "remouse_packet_processing_seconds_count {}", count);
+
+        out
+    }
+}
+
+/// Bind the metrics endpoint to `port` on localhost.
+/// This is separate from `serve` so that it can be bound before daemonizing, which only keeps the calling thread.
+pub fn bind(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+}
+
+/// Answer HTTP requests for `/metrics` on `listener` from a background thread.
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = respond(stream, &metrics) {
+                warn!(event = "metrics_error", error = e; "failed to answer metrics request: {}", e);
+            }
+        }
+    });
+}
+
+/// Read a single HTTP request from `stream` and respond to it.
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the headers, which are never needed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", String::from("not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("method not allowed\n"),
+        ),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let metrics = Metrics::default();
+        metrics.packet(20, Duration::from_micros(5));
+        metrics.packet(30, Duration::from_micros(5));
+        metrics.injected(Event::KeyDown);
+        metrics.dropped(Limit::HandshakeCooldown);
+        metrics.set_active_sessions(3);
+
+        let rendered = metrics.render();
+        assert!(rendered.starts_with(
+            "# HELP remouse_packets_received_total Packets received from clients.\n\
+             # TYPE remouse_packets_received_total counter\n\
+             remouse_packets_received_total 2\n"
+        ));
+        for line in &[
+            "remouse_received_bytes_total 50",
+            "# TYPE remouse_active_sessions gauge",
+            "remouse_active_sessions 3",
+            "remouse_injected_events_total{type=\"key_down\"} 1",
+            "remouse_injected_events_total{type=\"move\"} 0",
+            "remouse_packets_dropped_total{limit=\"handshake_cooldown\"} 1",
+        ] {
+            assert!(rendered.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn renders_a_cumulative_histogram() {
+        let metrics = Metrics::default();
+        metrics.packet(13, Duration::from_micros(20));
+        metrics.packet(13, Duration::from_millis(3));
+
+        let rendered = metrics.render();
+        for line in &[
+            "# TYPE remouse_packet_processing_seconds histogram",
+            "remouse_packet_processing_seconds_bucket{le=\"0.00001\"} 0",
+            "remouse_packet_processing_seconds_bucket{le=\"0.000025\"} 1",
+            "remouse_packet_processing_seconds_bucket{le=\"0.0025\"} 1",
+            "remouse_packet_processing_seconds_bucket{le=\"0.005\"} 2",
+            "remouse_packet_processing_seconds_bucket{le=\"+Inf\"} 2",
+            "remouse_packet_processing_seconds_sum 0.00302",
+            "remouse_packet_processing_seconds_count 2",
+        ] {
+            assert!(rendered.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn every_sample_is_described_with_quoted_labels() {
+        let rendered = Metrics::default().render();
+        let mut described: Vec<(String, String)> = Vec::new();
+
+        for line in rendered.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                assert!(help
+                    .split_once(' ')
+                    .is_some_and(|(_, text)| !text.is_empty()));
+                continue;
+            }
+            if let Some(kind) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = kind.split_once(' ').unwrap();
+                assert!(rendered.contains(&format!("# HELP {} ", name)));
+                described.push((name.to_string(), kind.to_string()));
+                continue;
+            }
+
+            let (sample, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "invalid value in {}", line);
+
+            let name = match sample.split_once('{') {
+                Some((name, labels)) => {
+                    let (label, value) = labels.strip_suffix('}').unwrap().split_once('=').unwrap();
+                    assert!(label.chars().all(|c| c.is_ascii_lowercase() || c == '_'));
+                    let value = value.strip_prefix('"').unwrap().strip_suffix('"').unwrap();
+                    assert!(
+                        !value.contains(&['"', '\\', '\n'][..]),
+                        "unescaped {}",
+                        line
+                    );
+                    name
+                }
+                None => sample,
+            };
+
+            let (family, kind) = described.last().unwrap();
+            match kind.as_str() {
+                "histogram" => assert!(name.starts_with(family.as_str())),
+                kind => {
+                    assert_eq!(name, family);
+                    assert_eq!(kind == "counter", name.ends_with("_total"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve(listener, Arc::new(Metrics::default()));
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.ends_with(&Metrics::default().render()));
+
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::keyboard::Key;
+use crate::layout::Layout;
+use enigo::{Enigo, KeyboardControllable, MouseButton, MouseControllable};
+
+/// Something which can inject keyboard and mouse events into the server's machine.
+pub trait Output {
+    fn key_down(&mut self, key: Key);
+    fn key_up(&mut self, key: Key);
+    fn mouse_down(&mut self, button: MouseButton);
+    fn mouse_up(&mut self, button: MouseButton);
+    /// Scroll vertically, where positive lengths scroll up.
+    fn mouse_scroll_y(&mut self, length: i32);
+    fn mouse_move_relative(&mut self, x: i32, y: i32);
+    /// Move the pointer to a position in pixels from the top left of the desktop.
+    fn mouse_move_to(&mut self, x: i32, y: i32);
+
+    /// Where the pointer is in pixels from the top left of the desktop, if the backend can find out.
+    fn mouse_location(&self) -> Option<(i32, i32)> {
+        None
+    }
+
+    /// Whether the backend types the character each key has on a layout rather than pressing physical keys,
+    /// in which case keys aren't translated between layouts before they reach it.
+    fn types_characters(&self) -> bool {
+        false
+    }
+
+    /// Set the layout of the client the keys come from, for backends which type characters.
+    fn set_layout(&mut self, _layout: Layout) {}
+}
+
+/// The output backends which can be selected with `--backend`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Enigo,
+    DryRun,
+    #[cfg(target_os = "linux")]
+    Uinput,
+}
+
+impl Backend {
+    /// Parse a backend from its command line name.
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "enigo" => Some(Backend::Enigo),
+            #[cfg(target_os = "linux")]
+            "uinput" => Some(Backend::Uinput),
+            _ => None,
+        }
+    }
+
+    /// Create the output for this backend.
+    pub fn create(self) -> std::io::Result<Box<dyn Output>> {
+        match self {
+            Backend::Enigo => Ok(Box::new(EnigoOutput {
+                enigo: Enigo::new(),
+                layout: Layout::Us,
+            })),
+            Backend::DryRun => Ok(Box::new(EventLog)),
+            #[cfg(target_os = "linux")]
+            Backend::Uinput => Ok(Box::new(crate::uinput::Device::new()?)),
+        }
+    }
+}
+
+/// Injects input with enigo. enigo can't press physical keys on every platform, so keys which type
+/// a character are injected as the character they type on the client's layout.
+pub struct EnigoOutput {
+    enigo: Enigo,
+    layout: Layout,
+}
+
+impl Output for EnigoOutput {
+    fn key_down(&mut self, key: Key) {
+        self.enigo.key_down(enigo_key(key, self.layout));
+    }
+
+    fn key_up(&mut self, key: Key) {
+        self.enigo.key_up(enigo_key(key, self.layout));
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        self.enigo.mouse_down(button);
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        self.enigo.mouse_up(button);
+    }
+
+    fn mouse_scroll_y(&mut self, length: i32) {
+        self.enigo.mouse_scroll_y(length);
+    }
+
+    fn mouse_move_relative(&mut self, x: i32, y: i32) {
+        self.enigo.mouse_move_relative(x, y);
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        self.enigo.mouse_move_to(x, y);
+    }
+
+    fn mouse_location(&self) -> Option<(i32, i32)> {
+        mouse_location()
+    }
+
+    fn types_characters(&self) -> bool {
+        true
+    }
+
+    fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+}
+
+/// An output which prints a human-readable description of every event instead of injecting it.
+pub struct EventLog;
+
+impl Output for EventLog {
+    fn key_down(&mut self, key: Key) {
+        println!("key down {:?}", key);
+    }
+
+    fn key_up(&mut self, key: Key) {
+        println!("key up {:?}", key);
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        println!("button down {:?}", button);
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        println!("button up {:?}", button);
+    }
+
+    fn mouse_scroll_y(&mut self, length: i32) {
+        match length {
+            1 => println!("scroll up"),
+            -1 => println!("scroll down"),
+            _ => println!("scroll {:+}", length),
+        }
+    }
+
+    fn mouse_move_relative(&mut self, x: i32, y: i32) {
+        if x != 0 || y != 0 {
+            println!("move {:+},{:+}", x, y);
+        }
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        println!("move to {},{}", x, y);
+    }
+}
+
+/// Find where the pointer is in pixels from the top left of the desktop.
+#[cfg(not(target_os = "linux"))]
+pub fn mouse_location() -> Option<(i32, i32)> {
+    Some(Enigo::mouse_location())
+}
+
+/// Find where the pointer is in pixels from the top left of the desktop, which needs X11.
+/// enigo can't tell on Linux, so this asks libxdo, which enigo already links.
+#[cfg(target_os = "linux")]
+pub fn mouse_location() -> Option<(i32, i32)> {
+    #[link(name = "xdo")]
+    extern "C" {
+        fn xdo_new(display: *const libc::c_char) -> *mut libc::c_void;
+        fn xdo_get_mouse_location(
+            xdo: *const libc::c_void,
+            x: *mut libc::c_int,
+            y: *mut libc::c_int,
+            screen: *mut libc::c_int,
+        ) -> libc::c_int;
+        fn xdo_free(xdo: *mut libc::c_void);
+    }
+
+    // libxdo complains on standard error when there's no display to open
+    std::env::var_os("DISPLAY")?;
+
+    let (mut x, mut y, mut screen) = (0, 0, 0);
+    let result = unsafe {
+        let xdo = xdo_new(std::ptr::null());
+        if xdo.is_null() {
+            return None;
+        }
+        let result = xdo_get_mouse_location(xdo, &mut x, &mut y, &mut screen);
+        xdo_free(xdo);
+        result
+    };
+
+    match result {
+        0 => Some((x, y)),
+        _ => None,
+    }
+}
+
+/// Convert a `Key` into the `enigo::Key` which types it, typing the character it has on `layout` if it has one.
+fn enigo_key(key: Key, layout: Layout) -> enigo::Key {
+    match key {
+        Key::Space => enigo::Key::Layout(' '),
+        Key::Enter => enigo::Key::Return,
+        Key::LeftShift => enigo::Key::Shift,
+        Key::LeftCtrl => enigo::Key::Control,
+        Key::Down => enigo::Key::DownArrow,
+        Key::Left => enigo::Key::LeftArrow,
+        Key::Right => enigo::Key::RightArrow,
+        Key::Up => enigo::Key::UpArrow,
+        Key::Backspace => enigo::Key::Backspace,
+        Key::Delete => enigo::Key::Delete,
+        Key::End => enigo::Key::End,
+        Key::Home => enigo::Key::Home,
+        Key::PageDown => enigo::Key::PageDown,
+        Key::PageUp => enigo::Key::PageUp,
+        Key::Tab => enigo::Key::Tab,
+        Key::CapsLock => enigo::Key::CapsLock,
+        Key::LeftAlt => enigo::Key::Alt,
+        Key::Escape => enigo::Key::Escape,
+        Key::F1 => enigo::Key::F1,
+        Key::F2 => enigo::Key::F2,
+        Key::F3 => enigo::Key::F3,
+        Key::F4 => enigo::Key::F4,
+        Key::F5 => enigo::Key::F5,
+        Key::F6 => enigo::Key::F6,
+        Key::F7 => enigo::Key::F7,
+        Key::F8 => enigo::Key::F8,
+        Key::F9 => enigo::Key::F9,
+        Key::F10 => enigo::Key::F10,
+        Key::F11 => enigo::Key::F11,
+        Key::F12 => enigo::Key::F12,
+        Key::RightShift => enigo::Key::Shift,
+        Key::RightCtrl => enigo::Key::Control,
+        Key::RightAlt => enigo::Key::Alt,
+        Key::LeftMeta => enigo::Key::Meta,
+        Key::RightMeta => enigo::Key::Meta,
+        // Every other key types a character on each layout
+        _ => enigo::Key::Layout(layout.character(key).unwrap_or(' ')),
+    }
+}
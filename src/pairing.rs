@@ -0,0 +1,136 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// How long a PIN remains valid after it is shown.
+pub const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How many wrong PINs the server accepts before it stops pairing until the operator unlocks it,
+/// so the PINs can't be guessed by reconnecting for a new one each time.
+pub const MAX_WRONG_PINS: u32 = 3;
+
+/// A random identity which a client generates once and sends whenever it connects,
+/// allowing the server to remember which clients it has paired with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientId(pub [u8; 16]);
+
+impl ClientId {
+    /// Parse a client ID from its hexadecimal representation.
+    pub fn from_hex(hex: &str) -> Option<ClientId> {
+        if hex.len() != 32 || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+
+        Some(ClientId(bytes))
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Load this machine's client ID, generating and saving a new one if there isn't one yet.
+pub fn load_or_create_client_id() -> std::io::Result<ClientId> {
+    let path = crate::config::data_dir().join("client_id");
+
+    if let Some(id) = fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| ClientId::from_hex(contents.trim()))
+    {
+        return Ok(id);
+    }
+
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let id = ClientId(bytes);
+
+    fs::create_dir_all(crate::config::data_dir())?;
+    fs::write(&path, format!("{}\n", id))?;
+
+    Ok(id)
+}
+
+/// Generate a random six digit PIN.
+pub fn generate_pin() -> u32 {
+    let mut bytes = [0; 4];
+    getrandom::getrandom(&mut bytes).unwrap();
+
+    u32::from_le_bytes(bytes) % 1_000_000
+}
+
+/// A client which has been shown a PIN and has not yet entered it.
+pub struct PendingPairing {
+    pub address: SocketAddr,
+    pub id: ClientId,
+    pub pin: u32,
+    pub started: Instant,
+}
+
+/// The IDs of every client which has successfully paired, kept in a file with one ID per line.
+pub struct TrustStore {
+    path: PathBuf,
+    ids: Vec<ClientId>,
+}
+
+impl TrustStore {
+    /// Load the trust store at `path`, which is treated as empty if it doesn't exist yet.
+    pub fn load(path: PathBuf) -> Result<TrustStore, String> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+
+        let ids = contents
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|id| !id.starts_with('#'))
+            .map(|id| {
+                ClientId::from_hex(id).ok_or(format!(
+                    "{}: invalid client ID {}",
+                    path.display(),
+                    id
+                ))
+            })
+            .collect::<Result<Vec<ClientId>, String>>()?;
+
+        Ok(TrustStore { path, ids })
+    }
+
+    pub fn contains(&self, id: &ClientId) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Remember a client ID, appending it to the trust store file along with a comment describing it.
+    pub fn add(&mut self, id: ClientId, comment: &str) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{} # {}", id, comment)?;
+
+        self.ids.push(id);
+
+        Ok(())
+    }
+}
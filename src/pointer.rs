@@ -0,0 +1,366 @@
+/// How the server scales the mouse movement it receives before applying it.
+pub struct Acceleration {
+    /// Multiplier applied to every movement.
+    sensitivity: f64,
+    profile: Profile,
+}
+
+/// How the multiplier changes with the speed of the movement, measured in counts per packet.
+pub enum Profile {
+    /// The same multiplier at every speed.
+    Flat,
+    /// The multiplier grows by the given factor for every count of speed.
+    Linear(f64),
+    /// The multiplier is interpolated between `(speed, multiplier)` points, sorted by speed.
+    Curve(Vec<(f64, f64)>),
+}
+
+impl Acceleration {
+    /// Parse the acceleration settings from their command line or config file values.
+    ///
+    /// `profile` is one of `flat`, `linear` or `curve`, where `linear` uses `factor` (0.1 by default)
+    /// and `curve` uses `curve`, a space separated list of `speed:multiplier` points such as `0:1 10:1.5 40:3`.
+    pub fn parse(
+        sensitivity: Option<&str>,
+        profile: Option<&str>,
+        factor: Option<&str>,
+        curve: Option<&str>,
+    ) -> Result<Acceleration, String> {
+        let sensitivity = match sensitivity {
+            Some(sensitivity) => {
+                positive(sensitivity).ok_or(format!("invalid sensitivity {}", sensitivity))?
+            }
+            None => 1.0,
+        };
+
+        let profile = match profile.unwrap_or("flat") {
+            "flat" => Profile::Flat,
+            "linear" => Profile::Linear(match factor {
+                Some(factor) => {
+                    positive(factor).ok_or(format!("invalid acceleration factor {}", factor))?
+                }
+                None => 0.1,
+            }),
+            "curve" => Profile::Curve(parse_curve(curve.ok_or("no acceleration curve given")?)?),
+            profile => return Err(format!("unknown acceleration profile {}", profile)),
+        };
+
+        Ok(Acceleration {
+            sensitivity,
+            profile,
+        })
+    }
+
+    /// Scale a movement, carrying the fractional part over to the next movement in `remainder`
+    /// so that slow movements still add up to whole pixels.
+    pub fn apply(&self, x: i16, y: i16, remainder: &mut (f64, f64)) -> (i32, i32) {
+        let speed = (x as f64).hypot(y as f64);
+        let multiplier = self.sensitivity * self.profile.multiplier(speed);
+
+        let scaled_x = x as f64 * multiplier + remainder.0;
+        let scaled_y = y as f64 * multiplier + remainder.1;
+        *remainder = (scaled_x.fract(), scaled_y.fract());
+
+        (scaled_x.trunc() as i32, scaled_y.trunc() as i32)
+    }
+}
+
+impl Profile {
+    fn multiplier(&self, speed: f64) -> f64 {
+        match self {
+            Profile::Flat => 1.0,
+            Profile::Linear(factor) => 1.0 + factor * speed,
+            Profile::Curve(points) => {
+                let first = points[0];
+                let last = points[points.len() - 1];
+
+                if speed <= first.0 {
+                    return first.1;
+                }
+
+                points
+                    .windows(2)
+                    .find(|pair| speed <= pair[1].0)
+                    .map_or(last.1, |pair| {
+                        let (from, to) = (pair[0], pair[1]);
+                        from.1 + (to.1 - from.1) * (speed - from.0) / (to.0 - from.0)
+                    })
+            }
+        }
+    }
+}
+
+/// Parse a number which must be finite and greater than zero, as anything else would stop or invert the pointer.
+fn positive(value: &str) -> Option<f64> {
+    value
+        .parse()
+        .ok()
+        .filter(|value: &f64| value.is_finite() && *value > 0.0)
+}
+
+/// Parse a curve made up of space separated `speed:multiplier` points.
+fn parse_curve(curve: &str) -> Result<Vec<(f64, f64)>, String> {
+    let mut points = curve
+        .split_whitespace()
+        .map(|point| {
+            point
+                .split_once(':')
+                .and_then(|(speed, multiplier)| {
+                    let speed = speed
+                        .parse()
+                        .ok()
+                        .filter(|speed: &f64| speed.is_finite() && *speed >= 0.0)?;
+                    Some((speed, positive(multiplier)?))
+                })
+                .ok_or(format!("invalid acceleration curve point {}", point))
+        })
+        .collect::<Result<Vec<(f64, f64)>, String>>()?;
+
+    if points.is_empty() {
+        return Err("acceleration curve has no points".to_string());
+    }
+
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    points.dedup_by(|a, b| a.0 == b.0);
+
+    Ok(points)
+}
+
+/// A rectangle of the screen which the pointer is kept inside, in pixels from the top left of the desktop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Region {
+    /// Parse a region given as `x,y,width,height`, which must end within the range of an `i32`.
+    pub fn parse(region: &str) -> Result<Region, String> {
+        let values = region
+            .split(',')
+            .map(|value| value.trim().parse().ok())
+            .collect::<Option<Vec<i32>>>();
+
+        match values.as_deref() {
+            Some(&[x, y, width, height])
+                if width > 0
+                    && height > 0
+                    && x.checked_add(width).is_some()
+                    && y.checked_add(height).is_some() =>
+            {
+                Ok(Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                })
+            }
+            _ => Err(format!(
+                "invalid region {}, expected x,y,width,height",
+                region
+            )),
+        }
+    }
+
+    /// Find the region covered by monitor `index`, numbered as by `xrandr --listmonitors`.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub fn monitor(index: usize) -> Result<Region, String> {
+        let monitors = xrandr("--listmonitors")
+            .map_err(|e| format!("--monitor needs xrandr to find the monitors: {}", e))?;
+
+        // Each monitor is listed like ` 0: +*DP-1 2560/597x1440/336+0+0  DP-1`
+        monitors
+            .lines()
+            .skip(1)
+            .nth(index)
+            .and_then(|line| {
+                let geometry = line.split_whitespace().nth(2)?;
+                let mut parts = geometry.split('+');
+                let (width, height) = parts.next()?.split_once('x')?;
+                let size = |size: &str| size.split('/').next()?.parse().ok();
+
+                Some(Region {
+                    width: size(width)?,
+                    height: size(height)?,
+                    x: parts.next()?.parse().ok()?,
+                    y: parts.next()?.parse().ok()?,
+                })
+            })
+            .ok_or(format!("no monitor {}", index))
+    }
+
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    pub fn monitor(_index: usize) -> Result<Region, String> {
+        Err("--monitor is only supported with xrandr, use --confine instead".to_string())
+    }
+
+    /// Find the region covered by the whole desktop, from the current screen size reported by xrandr.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub fn desktop() -> Result<Region, String> {
+        let screens = xrandr("--current")?;
+
+        // The first line is like `Screen 0: minimum 320 x 200, current 4480 x 1440, maximum 16384 x 16384`
+        screens
+            .lines()
+            .next()
+            .and_then(|line| {
+                line.split(", ")
+                    .find_map(|part| part.strip_prefix("current "))
+            })
+            .and_then(|size| {
+                let (width, height) = size.split_once(" x ")?;
+
+                Some(Region {
+                    x: 0,
+                    y: 0,
+                    width: width.trim().parse().ok()?,
+                    height: height.trim().parse().ok()?,
+                })
+            })
+            .ok_or_else(|| "xrandr didn't report the screen size".to_string())
+    }
+
+    pub fn center(self) -> (i32, i32) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+
+    /// Move a position to the nearest point inside the region.
+    pub fn clamp(self, x: i32, y: i32) -> (i32, i32) {
+        (
+            x.clamp(self.x, self.x + self.width - 1),
+            y.clamp(self.y, self.y + self.height - 1),
+        )
+    }
+}
+
+/// Run `xrandr` with a single argument, returning what it printed.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn xrandr(argument: &str) -> Result<String, String> {
+    let output = match std::process::Command::new("xrandr").arg(argument).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err("xrandr isn't installed".to_string())
+        }
+        Err(e) => return Err(format!("failed to run xrandr: {}", e)),
+    };
+
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => Err(format!(
+            "xrandr failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acceleration(
+        sensitivity: &str,
+        profile: &str,
+        factor: Option<&str>,
+        curve: Option<&str>,
+    ) -> Acceleration {
+        Acceleration::parse(Some(sensitivity), Some(profile), factor, curve).unwrap()
+    }
+
+    #[test]
+    fn defaults_to_unscaled_movement() {
+        let acceleration = Acceleration::parse(None, None, None, None).unwrap();
+        let mut remainder = (0.0, 0.0);
+
+        assert_eq!(acceleration.apply(7, -3, &mut remainder), (7, -3));
+        assert_eq!(remainder, (0.0, 0.0));
+    }
+
+    #[test]
+    fn scales_by_the_sensitivity() {
+        let acceleration = acceleration("2.5", "flat", None, None);
+        let mut remainder = (0.0, 0.0);
+
+        assert_eq!(acceleration.apply(4, -2, &mut remainder), (10, -5));
+    }
+
+    #[test]
+    fn carries_fractions_over_to_the_next_movement() {
+        let acceleration = acceleration("0.5", "flat", None, None);
+        let mut remainder = (0.0, 0.0);
+
+        assert_eq!(acceleration.apply(1, -1, &mut remainder), (0, 0));
+        assert_eq!(acceleration.apply(1, -1, &mut remainder), (1, -1));
+        assert_eq!(acceleration.apply(1, -1, &mut remainder), (0, 0));
+    }
+
+    #[test]
+    fn linear_acceleration_grows_with_speed() {
+        let acceleration = acceleration("1", "linear", Some("0.1"), None);
+        let mut remainder = (0.0, 0.0);
+
+        assert_eq!(acceleration.apply(1, 0, &mut remainder).0, 1);
+        // At a speed of 10 the multiplier is 2
+        assert_eq!(acceleration.apply(6, 8, &mut remainder), (12, 16));
+    }
+
+    #[test]
+    fn curves_interpolate_between_points_and_clamp_outside_them() {
+        let curve = Profile::Curve(parse_curve("10:2 0:1 20:4").unwrap());
+
+        assert_eq!(curve.multiplier(0.0), 1.0);
+        assert_eq!(curve.multiplier(5.0), 1.5);
+        assert_eq!(curve.multiplier(15.0), 3.0);
+        assert_eq!(curve.multiplier(100.0), 4.0);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(Acceleration::parse(Some("fast"), None, None, None).is_err());
+        assert!(Acceleration::parse(None, Some("quadratic"), None, None).is_err());
+        assert!(Acceleration::parse(None, Some("linear"), Some("x"), None).is_err());
+        assert!(Acceleration::parse(None, Some("curve"), None, None).is_err());
+        assert!(Acceleration::parse(None, Some("curve"), None, Some("")).is_err());
+        assert!(Acceleration::parse(None, Some("curve"), None, Some("1:2 3")).is_err());
+
+        for number in &["nan", "inf", "-inf", "0", "-1.5"] {
+            assert!(Acceleration::parse(Some(number), None, None, None).is_err());
+            assert!(Acceleration::parse(None, Some("linear"), Some(number), None).is_err());
+            let curve = format!("0:1 10:{}", number);
+            assert!(Acceleration::parse(None, Some("curve"), None, Some(&curve)).is_err());
+        }
+        for speed in &["nan", "inf", "-1"] {
+            let curve = format!("0:1 {}:2", speed);
+            assert!(Acceleration::parse(None, Some("curve"), None, Some(&curve)).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!(
+            Region::parse("-1920, 0,1920,1080"),
+            Ok(Region {
+                x: -1920,
+                y: 0,
+                width: 1920,
+                height: 1080,
+            })
+        );
+        assert!(Region::parse("0,0,1920").is_err());
+        assert!(Region::parse("0,0,0,1080").is_err());
+        assert!(Region::parse("0,0,wide,1080").is_err());
+        assert!(Region::parse("2147483000,0,1000,10").is_err());
+        assert!(Region::parse("0,2147483000,10,1000").is_err());
+    }
+
+    #[test]
+    fn clamps_positions_inside_regions() {
+        let region = Region::parse("100,50,200,100").unwrap();
+
+        assert_eq!(region.clamp(150, 80), (150, 80));
+        assert_eq!(region.clamp(0, 0), (100, 50));
+        assert_eq!(region.clamp(1000, 1000), (299, 149));
+        assert_eq!(region.center(), (200, 100));
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+/// The limits the server puts on each client to protect itself from floods of packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// The packets per second accepted from each session, or 0 for no limit.
+    pub packet_rate: f64,
+    /// The input events per second injected for each session, or 0 for no limit.
+    pub event_rate: f64,
+    /// How long handshake packets from an IP address are ignored after one is rejected.
+    pub handshake_cooldown: Duration,
+}
+
+impl Limits {
+    /// Parse the limits from their command line or config file values, where the cooldown is in milliseconds.
+    pub fn parse(
+        packet_rate: Option<&str>,
+        event_rate: Option<&str>,
+        handshake_cooldown: Option<&str>,
+    ) -> Result<Limits, String> {
+        let rate = |rate: Option<&str>, default: f64| match rate {
+            Some(rate) => rate
+                .parse()
+                .ok()
+                .filter(|rate: &f64| *rate >= 0.0)
+                .ok_or(format!("invalid rate limit {}", rate)),
+            None => Ok(default),
+        };
+
+        Ok(Limits {
+            packet_rate: rate(packet_rate, 2000.0)?,
+            event_rate: rate(event_rate, 2000.0)?,
+            handshake_cooldown: match handshake_cooldown {
+                Some(ms) => Duration::from_millis(
+                    ms.parse()
+                        .map_err(|_| format!("invalid handshake cooldown {}", ms))?,
+                ),
+                None => Duration::from_secs(1),
+            },
+        })
+    }
+}
+
+/// A token bucket, which allows bursts of up to one second's worth of tokens and refills at a steady rate.
+pub struct TokenBucket {
+    /// The tokens added per second, or 0 if the bucket never runs out.
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `count` tokens if there are enough, returning whether there were.
+    /// Taking more than a full bucket only needs the bucket to be full.
+    pub fn take(&mut self, count: f64) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = Instant::now();
+
+        let count = count.min(self.rate);
+        if self.tokens >= count {
+            self.tokens -= count;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_refills_steadily() {
+        let mut bucket = TokenBucket::new(10.0);
+
+        assert!(bucket.take(4.0));
+        assert!(bucket.take(6.0));
+        assert!(!bucket.take(1.0));
+
+        // Half a second refills half the bucket
+        bucket.last_refill -= Duration::from_millis(500);
+        assert!(bucket.take(5.0));
+        assert!(!bucket.take(1.0));
+    }
+
+    #[test]
+    fn bursts_are_capped_at_one_second() {
+        let mut bucket = TokenBucket::new(10.0);
+
+        bucket.last_refill -= Duration::from_secs(60);
+        assert!(bucket.take(10.0));
+        assert!(!bucket.take(1.0));
+    }
+
+    #[test]
+    fn large_takes_only_need_a_full_bucket() {
+        let mut bucket = TokenBucket::new(10.0);
+
+        assert!(bucket.take(500.0));
+        assert!(!bucket.take(500.0));
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut bucket = TokenBucket::new(0.0);
+
+        for _ in 0..1000 {
+            assert!(bucket.take(100.0));
+        }
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(
+            Limits::parse(None, None, None),
+            Ok(Limits {
+                packet_rate: 2000.0,
+                event_rate: 2000.0,
+                handshake_cooldown: Duration::from_secs(1),
+            })
+        );
+        assert_eq!(
+            Limits::parse(Some("0"), Some("150.5"), Some("250")),
+            Ok(Limits {
+                packet_rate: 0.0,
+                event_rate: 150.5,
+                handshake_cooldown: Duration::from_millis(250),
+            })
+        );
+        assert!(Limits::parse(Some("-1"), None, None).is_err());
+        assert!(Limits::parse(None, Some("lots"), None).is_err());
+        assert!(Limits::parse(None, None, Some("1.5")).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::config::Config;
+use crate::keyboard::Key;
+use std::collections::HashMap;
+
+/// A table of keys which are replaced before they are pressed on the server's machine.
+///
+/// Tables are kept in the config file in sections named `remap.<profile>`, so there can be one
+/// profile for each kind of machine being controlled. Each line maps a key to another key, to a
+/// chord of keys joined with `+`, or to `none` to disable it:
+///
+/// ```
+/// [remap.mac]
+/// LeftCtrl = LeftMeta
+/// LeftMeta = LeftCtrl
+/// CapsLock = LeftCtrl
+/// End = Ctrl+Alt+Delete
+/// Backquote = none
+/// ```
+pub struct Remap {
+    mappings: HashMap<Key, Vec<Key>>,
+}
+
+impl Remap {
+    /// A table which leaves every key as it is.
+    pub fn identity() -> Remap {
+        Remap {
+            mappings: HashMap::new(),
+        }
+    }
+
+    /// Load the table for `profile` from the config file.
+    pub fn load(config: &Config, profile: &str) -> Result<Remap, String> {
+        let section = format!("remap.{}", profile);
+        let mut mappings = HashMap::new();
+
+        for (from, to) in config.entries(&section) {
+            let from_key = Key::from_name(from).ok_or(format!("unknown key {}", from))?;
+            let to_keys = match to.eq_ignore_ascii_case("none") {
+                true => Vec::new(),
+                false => to
+                    .split('+')
+                    .map(|name| {
+                        Key::from_name(name.trim()).ok_or(format!("unknown key {}", name.trim()))
+                    })
+                    .collect::<Result<Vec<Key>, String>>()?,
+            };
+
+            mappings.insert(from_key, to_keys);
+        }
+
+        if mappings.is_empty() {
+            return Err(format!("no key mappings found in [{}]", section));
+        }
+
+        Ok(Remap { mappings })
+    }
+
+    /// Replace each of the given held keys with the keys it is mapped to, keeping their order.
+    pub fn apply(&self, keys: &[Key]) -> Vec<Key> {
+        let mut mapped: Vec<Key> = Vec::new();
+
+        for key in keys {
+            let replacements = match self.mappings.get(key) {
+                Some(replacements) => replacements.as_slice(),
+                None => std::slice::from_ref(key),
+            };
+
+            for replacement in replacements {
+                if !mapped.contains(replacement) {
+                    mapped.push(*replacement);
+                }
+            }
+        }
+
+        mapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn remap(contents: &str) -> Result<Remap, String> {
+        Remap::load(&config::parse(contents).unwrap(), "test")
+    }
+
+    #[test]
+    fn replaces_keys_with_chords_and_disables_keys() {
+        let remap = remap(
+            "[remap.test]\n\
+             CapsLock = Escape\n\
+             End = Ctrl+Alt+Delete\n\
+             Backquote = none\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            remap.apply(&[Key::CapsLock, Key::A]),
+            vec![Key::Escape, Key::A]
+        );
+        assert_eq!(
+            remap.apply(&[Key::End]),
+            vec![Key::LeftCtrl, Key::LeftAlt, Key::Delete]
+        );
+        assert_eq!(remap.apply(&[Key::Backquote]), vec![]);
+    }
+
+    #[test]
+    fn swaps_keys_without_duplicating_them() {
+        let remap = remap(
+            "[remap.test]\n\
+             LeftCtrl = LeftMeta\n\
+             LeftMeta = LeftCtrl\n\
+             CapsLock = LeftCtrl\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            remap.apply(&[Key::LeftCtrl, Key::LeftMeta]),
+            vec![Key::LeftMeta, Key::LeftCtrl]
+        );
+        assert_eq!(
+            remap.apply(&[Key::LeftMeta, Key::CapsLock]),
+            vec![Key::LeftCtrl]
+        );
+    }
+
+    #[test]
+    fn keys_without_a_flag_can_be_remapped() {
+        let remap = remap(
+            "[remap.test]\n\
+             F1 = Escape\n\
+             RightCtrl = RightMeta\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            remap.apply(&[Key::F1, Key::RightCtrl]),
+            vec![Key::Escape, Key::RightMeta]
+        );
+    }
+
+    #[test]
+    fn identity_leaves_keys_alone() {
+        assert_eq!(
+            Remap::identity().apply(&[Key::Q, Key::LeftShift]),
+            vec![Key::Q, Key::LeftShift]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_empty_profiles() {
+        assert!(remap("[remap.test]\nHyper = A\n").is_err());
+        assert!(remap("[remap.test]\nA = Ctrl+Hyper\n").is_err());
+        assert!(remap("[remap.other]\nA = B\n").is_err());
+    }
+}
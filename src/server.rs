@@ -1,30 +1,169 @@
-use crate::keyboard::flags_to_pressed_keys;
-use enigo::*;
-use std::{convert::TryInto, net::UdpSocket};
+use crate::access::AccessList;
+use crate::address::{self, canonical_ip};
+use crate::control::{Command, ControlSocket};
+use crate::daemon;
+use crate::discovery;
+use crate::hooks::Hooks;
+use crate::keyboard::{flags_to_pressed_keys, Key};
+use crate::layout::Translation;
+use crate::mdns::Responder;
+use crate::metrics::{Event, Limit, Metrics};
+use crate::output::{Backend, Output};
+use crate::pairing::{self, ClientId, PendingPairing, TrustStore, MAX_WRONG_PINS, PAIRING_TIMEOUT};
+use crate::pointer::{Acceleration, Region};
+use crate::ratelimit::{Limits, TokenBucket};
+use crate::remap::Remap;
+use crate::session::{Policy, Session, SESSION_TIMEOUT};
+use crate::signals::{self, Signal};
+use enigo::MouseButton;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The packet a client sends to start a session, with only the connect bit set.
+const CONNECT_MESSAGE: [u8; 13] = [0, 0, 0, 0, 0b0100_0000, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// The packet a client sends to enter a PIN, with the connect and pairing bits set.
+const PAIR_MESSAGE: [u8; 13] = [0, 0, 0, 0, 0b1100_0000, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// The server's responses to a handshake.
+/// `REJECTED` is also sent to a client when its session is closed with `remouse ctl kick`.
+const ACCEPTED: u8 = 0xff;
+const REJECTED: u8 = 0x00;
+const PIN_REQUIRED: u8 = 0x01;
+
+/// The most IP addresses which can be cooling down at once, so a flood from many addresses can't use up memory.
+const MAX_COOLDOWNS: usize = 4096;
+
+/// The most clients which can be waiting to enter a PIN at once, so a flood of connects can't use up memory.
+const MAX_PENDING_PAIRINGS: usize = 64;
+
+/// How long input packets from a kicked client are dropped without a response,
+/// in case it is too old to stop when it is told it was kicked.
+const KICK_TOMBSTONE: Duration = Duration::from_secs(10);
+
+/// A handshake packet from a client without a session.
+enum Handshake {
+    /// A request to connect, along with the client's ID if it sent one.
+    Connect(Option<ClientId>),
+    /// A PIN entered by the user of the client with the given ID.
+    Pair(ClientId, u32),
+}
 
 pub struct Server {
-    output_manager: Enigo,
+    output_manager: Box<dyn Output>,
     socket: UdpSocket,
+    settings: Settings,
+    /// The clients which have paired, if pairing is required.
+    trust_store: Option<TrustStore>,
+    pending_pairings: Vec<PendingPairing>,
+    /// How many wrong PINs have been entered since pairing was last unlocked or succeeded.
+    wrong_pins: u32,
+    sessions: Vec<Session>,
+    next_session_id: u32,
+    /// The session in control under the `LastActive` policy.
+    controller: Option<u32>,
+    /// The button flags currently held down on this machine.
+    held_button_flags: u8,
+    /// The keys currently held down on this machine, after remapping.
+    held_keys: Vec<Key>,
+    /// How often systemd's watchdog must be notified, if it is enabled.
+    watchdog: Option<Duration>,
+    last_watchdog: Instant,
+    metrics: Arc<Metrics>,
+    listeners: Listeners,
+    /// Whether injecting input has been paused with `remouse ctl pause`.
+    paused: bool,
+    /// Where the pointer was last moved to, if it is being confined to a region.
+    pointer: Option<(i32, i32)>,
+    /// The IP addresses whose handshake packets are ignored until the given time, after one was rejected.
+    cooldowns: HashMap<IpAddr, Instant>,
+    /// The addresses of kicked clients, whose input packets are dropped until the given time.
+    kicked: HashMap<SocketAddr, Instant>,
 }
 
-/// Initialise the server, starting the UDP socket and returning the server instance.
-pub fn init() -> Server {
-    let manager = Enigo::new();
-    let socket = UdpSocket::bind("0.0.0.0:42069").unwrap();
+/// The sockets the server answers requests on besides the one clients send input to.
+/// Each is `None` if it is turned off or couldn't be created.
+pub struct Listeners {
+    /// The socket `remouse ctl` sends commands to.
+    pub control: Option<ControlSocket>,
+    /// The socket `remouse discover` broadcasts discovery requests to.
+    pub discovery: Option<UdpSocket>,
+    /// The multicast DNS responder advertising the server as a DNS-SD service.
+    pub mdns: Option<Responder>,
+}
 
-    Server {
+/// Settings which affect how the server treats clients and their input.
+pub struct Settings {
+    pub access: AccessList,
+    pub policy: Policy,
+    pub acceleration: Acceleration,
+    /// The region the pointer is kept inside, if any.
+    pub confine: Option<Region>,
+    pub remap: Remap,
+    pub limits: Limits,
+    pub hooks: Hooks,
+    /// The layout translation applied to keys, or `None` to press the same physical keys as the client.
+    pub translation: Option<Translation>,
+}
+
+/// Initialise the server, creating the output backend, starting the UDP socket and returning the server instance.
+pub fn init(
+    backend: Backend,
+    bind_address: SocketAddr,
+    settings: Settings,
+    trust_store: Option<TrustStore>,
+    metrics: Arc<Metrics>,
+    listeners: Listeners,
+) -> std::io::Result<Server> {
+    let mut manager = backend.create()?;
+    if let Some(translation) = settings.translation {
+        manager.set_layout(translation.client);
+    }
+    let socket = address::bind(bind_address)?;
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+    Ok(Server {
         output_manager: manager,
         socket,
-    }
+        settings,
+        trust_store,
+        pending_pairings: Vec::new(),
+        wrong_pins: 0,
+        sessions: Vec::new(),
+        next_session_id: 0,
+        controller: None,
+        held_button_flags: 0,
+        held_keys: Vec::new(),
+        watchdog: daemon::watchdog_interval(),
+        last_watchdog: Instant::now(),
+        metrics,
+        listeners,
+        paused: false,
+        pointer: None,
+        cooldowns: HashMap::new(),
+        kicked: HashMap::new(),
+    })
 }
 
 /// Run the server, listening for events and acting upon them.
-/// Recieves UDP packets of length 13 bytes in the following format:
+/// Recieves UDP packets of at least 13 bytes in the following format:
 ///
 /// - bytes 0-1:  x coordinate of mouse as little endian `i32`
 /// - bytes 2-3:  y coordinate of mouse as little endian `i32`
 /// - byte 4:     mouse button and connection info flags
 /// - bytes 5-13: key flags, mapping described in `keyboard.rs`
+/// - byte 13:    number of held keys (optional)
+/// - bytes 14-:  USB HID usage IDs of the held keys (optional)
+///
+/// If the held keys are included, they are used instead of the key flags, as they identify
+/// the physical position of each key and include keys which don't have a flag.
 ///
 /// The mouse button and connection info flags are one byte.
 /// Their meanings are as follows from the least significant bit:
@@ -36,87 +175,675 @@ pub fn init() -> Server {
 /// - scroll down
 /// - disconnect bit (1 triggers a clean disconnect)
 /// - connect bit (1 signals that the connection is ready)
+/// - pairing bit (1 signals that the packet contains a PIN)
 ///
-/// The final bit is currently unused.
-pub fn run(server: &mut Server) {
-    let mut button_flags: u8 = 0;
-    let mut key_flags: u64 = 0;
-    let mut previous_button_flags: u8;
-    let mut previous_key_flags: u64;
-
-    let mut acknowledged_connection = false;
-    let button_types: Vec<MouseButton> =
-        vec![MouseButton::Left, MouseButton::Right, MouseButton::Middle];
-
+/// Connect messages are followed by the client's 16 byte ID. If the server requires pairing and
+/// doesn't trust that ID, it responds with `0x01` and shows a PIN, which the client sends back in
+/// a pair message followed by its ID and the PIN as a little endian `u32`.
+///
+/// Each client which completes the handshake gets its own session, and the server's `Policy`
+/// decides which sessions' input is applied. Sessions which stop sending packets time out.
+///
+/// Returns the signal when one is received, leaving it to the caller to reload the settings or stop.
+/// Dropping the server releases every held key and button.
+pub fn run(server: &mut Server) -> Signal {
     loop {
-        let mut buf = [0; 13];
-        let (_, addr) = server.socket.recv_from(&mut buf).unwrap();
-
-        if !acknowledged_connection {
-            let mut connect_message: [u8; 13] = [0; 13];
-            connect_message[4] = 0b0100_0000;
-
-            if buf == connect_message {
-                println!("receiving input from {}", addr.ip());
-                server.socket.send_to(&[0xff_u8], addr).unwrap();
-                acknowledged_connection = true;
-            } else {
-                println!("received malformed request from {}, ignoring", addr.ip());
-                server.socket.send_to(&[0x00_u8], addr).unwrap();
+        if let Some(signal) = signals::take() {
+            return signal;
+        }
+
+        if let Some(interval) = server.watchdog {
+            if server.last_watchdog.elapsed() >= interval {
+                daemon::notify("WATCHDOG=1");
+                server.last_watchdog = Instant::now();
+            }
+        }
+
+        if let Some(request) = server
+            .listeners
+            .control
+            .as_mut()
+            .and_then(ControlSocket::accept)
+        {
+            let response = match request.command {
+                Ok(command) => control(server, command),
+                Err(ref e) => format!("error: {}\n", e),
             };
+            request.reply(&response);
+        }
+
+        answer_discovery(server);
+        if let Some(mdns) = &server.listeners.mdns {
+            mdns.answer();
+        }
+
+        let mut buf = [0; 64];
+        let received = server.socket.recv_from(&mut buf);
+
+        expire_sessions(server);
+
+        let (length, addr) = match received {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(e) => {
+                error!(event = "receive_error", error = e; "failed to receive packet: {}", e);
+                continue;
+            }
+        };
 
-            continue;
+        let start = Instant::now();
+        match server.sessions.iter().position(|s| s.address == addr) {
+            Some(index) => handle_input(server, index, &buf[0..length]),
+            None => handshake(server, addr, &buf[0..length]),
         }
+        server.metrics.packet(length, start.elapsed());
+    }
+}
 
-        let x = i16::from_le_bytes(buf[0..2].try_into().unwrap());
-        let y = i16::from_le_bytes(buf[2..4].try_into().unwrap());
-        previous_button_flags = button_flags;
-        previous_key_flags = key_flags;
-        button_flags = buf[4];
-        key_flags = u64::from_le_bytes(buf[5..13].try_into().unwrap());
+/// Respond to a packet from an address without a session, starting one if it is a valid connect message.
+fn handshake(server: &mut Server, addr: SocketAddr, packet: &[u8]) {
+    let ip = canonical_ip(addr.ip());
 
-        // Check the button flags and update the mouse state accordingly
-        let button_states = button_flags.to_bools();
-        let previous_button_states = previous_button_flags.to_bools();
-        for button_id in 0..button_types.len() {
-            if button_states[button_id] ^ previous_button_states[button_id] {
-                match button_states[button_id] {
-                    true => server.output_manager.mouse_down(button_types[button_id]),
-                    false => server.output_manager.mouse_up(button_types[button_id]),
+    // A kicked client may keep sending input for a moment, which would otherwise be rejected as malformed
+    server.kicked.retain(|_, until| *until > Instant::now());
+    if server.kicked.contains_key(&addr) && parse_handshake(packet).is_none() {
+        return;
+    }
+
+    if server
+        .cooldowns
+        .get(&ip)
+        .is_some_and(|until| *until > Instant::now())
+    {
+        server.metrics.dropped(Limit::HandshakeCooldown);
+        return;
+    }
+
+    if let Err(reason) = server.settings.access.check(ip) {
+        return reject(server, addr, &reason);
+    }
+
+    let request = match parse_handshake(packet) {
+        Some(request) => request,
+        None => {
+            warn!(event = "rejected_packet", peer = ip; "received malformed request from {}, ignoring", ip);
+            server.metrics.rejected();
+            start_cooldown(server, ip);
+            send(server, &[REJECTED], addr);
+            return;
+        }
+    };
+
+    if server.settings.policy == Policy::Exclusive && !server.sessions.is_empty() {
+        return reject(server, addr, "another client has control");
+    }
+
+    let trust_store = match &mut server.trust_store {
+        Some(trust_store) => trust_store,
+        None => return start_session(server, addr),
+    };
+
+    match request {
+        Handshake::Connect(Some(id)) if trust_store.contains(&id) => start_session(server, addr),
+        Handshake::Connect(Some(_)) if server.wrong_pins >= MAX_WRONG_PINS => reject(
+            server,
+            addr,
+            "pairing is locked after too many wrong PINs, run `remouse ctl unlock-pairing` to allow it again",
+        ),
+        Handshake::Connect(Some(id)) => {
+            // Forget pairings which can no longer succeed, and refuse new ones if too many are still waiting
+            server
+                .pending_pairings
+                .retain(|p| p.address != addr && p.started.elapsed() < PAIRING_TIMEOUT);
+            if server.pending_pairings.len() >= MAX_PENDING_PAIRINGS {
+                return reject(server, addr, "too many pairing requests in progress");
+            }
+
+            let pin = pairing::generate_pin();
+            info!(
+                event = "pairing_request", peer = ip, client = id;
+                "pairing request from {}, enter PIN {:06} on the client to accept it", ip, pin
+            );
+
+            server.pending_pairings.push(PendingPairing {
+                address: addr,
+                id,
+                pin,
+                started: Instant::now(),
+            });
+            send(server, &[PIN_REQUIRED], addr);
+        }
+        Handshake::Connect(None) => reject(server, addr, "client does not support pairing"),
+        Handshake::Pair(id, pin) => {
+            let pending_pairings = &mut server.pending_pairings;
+            let pending = pending_pairings
+                .iter()
+                .position(|p| p.address == addr && p.id == id)
+                .map(|index| pending_pairings.remove(index));
+
+            // Each PIN can only be tried once, so a wrong guess requires reconnecting for a new PIN,
+            // and too many wrong guesses lock pairing altogether
+            match pending {
+                Some(p) if p.pin == pin && p.started.elapsed() < PAIRING_TIMEOUT => {
+                    if let Err(e) = trust_store.add(id, &format!("paired from {}", ip)) {
+                        error!(event = "trust_store_error", error = e; "failed to save trusted client: {}", e);
+                    }
+
+                    info!(event = "paired", peer = ip, client = id; "paired with {}", ip);
+                    server.wrong_pins = 0;
+                    start_session(server, addr);
                 }
+                Some(p) if p.pin != pin => {
+                    server.wrong_pins += 1;
+                    if server.wrong_pins == MAX_WRONG_PINS {
+                        warn!(
+                            event = "pairing_locked", peer = ip;
+                            "{} wrong PINs entered, pairing is locked until `remouse ctl unlock-pairing`", MAX_WRONG_PINS
+                        );
+                    }
+                    reject(server, addr, "incorrect PIN");
+                }
+                Some(_) => reject(server, addr, "expired PIN"),
+                None => reject(server, addr, "no pairing in progress"),
+            }
+        }
+    }
+}
+
+/// Answer every waiting discovery request from an address which is allowed to connect.
+fn answer_discovery(server: &Server) {
+    let socket = match &server.listeners.discovery {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    let local_address = server.socket.local_addr().ok();
+    let port = local_address.map_or(0, |addr| addr.port());
+    let ip = local_address
+        .map(|addr| addr.ip())
+        .filter(|ip| !ip.is_unspecified());
+    let busy = server.settings.policy == Policy::Exclusive && !server.sessions.is_empty();
+
+    while let Some(addr) = discovery::receive(socket) {
+        if server
+            .settings
+            .access
+            .check(canonical_ip(addr.ip()))
+            .is_ok()
+        {
+            discovery::answer(socket, addr, ip, port, busy);
+        }
+    }
+}
+
+/// Refuse a connection from `addr`.
+fn reject(server: &mut Server, addr: SocketAddr, reason: &str) {
+    let ip = canonical_ip(addr.ip());
+    warn!(
+        event = "rejected", peer = ip, reason = reason;
+        "rejected connection from {}: {}", ip, reason
+    );
+    server.metrics.rejected();
+    server.metrics.handshake_failed();
+    start_cooldown(server, ip);
+    send(server, &[REJECTED], addr);
+}
+
+/// Send a handshake response to `addr`. Failures are logged rather than stopping the server,
+/// as the address may be spoofed or unreachable.
+fn send(server: &Server, packet: &[u8], addr: SocketAddr) {
+    if let Err(e) = server.socket.send_to(packet, addr) {
+        let ip = canonical_ip(addr.ip());
+        warn!(event = "send_error", peer = ip, error = e; "failed to send to {}: {}", ip, e);
+    }
+}
+
+/// Ignore handshakes from `ip` for a while, so a client can't flood the server with them.
+fn start_cooldown(server: &mut Server, ip: IpAddr) {
+    let cooldown = server.settings.limits.handshake_cooldown;
+    if cooldown == Duration::ZERO {
+        return;
+    }
+
+    if server.cooldowns.len() >= MAX_COOLDOWNS {
+        server.cooldowns.retain(|_, until| *until > Instant::now());
+    }
+
+    // If every address is still cooling down, the new one goes without rather than growing the map
+    if server.cooldowns.len() < MAX_COOLDOWNS || server.cooldowns.contains_key(&ip) {
+        server.cooldowns.insert(ip, Instant::now() + cooldown);
+    }
+}
+
+/// Parse a connect or pair message.
+fn parse_handshake(packet: &[u8]) -> Option<Handshake> {
+    let id = packet
+        .get(13..29)
+        .map(|id| ClientId(id.try_into().unwrap()));
+
+    if packet.get(0..13)? == CONNECT_MESSAGE && (packet.len() == 13 || packet.len() == 29) {
+        Some(Handshake::Connect(id))
+    } else if packet.get(0..13)? == PAIR_MESSAGE && packet.len() == 33 {
+        let pin = u32::from_le_bytes(packet[29..33].try_into().unwrap());
+        Some(Handshake::Pair(id?, pin))
+    } else {
+        None
+    }
+}
+
+/// Start a session for the client at `addr` and acknowledge it.
+fn start_session(server: &mut Server, addr: SocketAddr) {
+    server.next_session_id += 1;
+    let session = Session::new(server.next_session_id, addr, &server.settings.limits);
+
+    let ip = canonical_ip(addr.ip());
+    info!(
+        event = "connect", peer = ip, session = session.id;
+        "receiving input from {} (session {})", ip, session.id
+    );
+    server.settings.hooks.connected(&session);
+    server.sessions.push(session);
+    server.metrics.set_active_sessions(server.sessions.len());
+    send(server, &[ACCEPTED], addr);
+}
+
+/// Apply an input packet from the session at `index`.
+fn handle_input(server: &mut Server, index: usize, buf: &[u8]) {
+    if buf.len() < 13 {
+        let session = &server.sessions[index];
+        let ip = canonical_ip(session.address.ip());
+        debug!(
+            event = "rejected_packet", peer = ip, session = session.id;
+            "ignoring short packet from {}", ip
+        );
+        server.metrics.rejected();
+        return;
+    }
+
+    // The client may have restarted on the same port, in which case it is still waiting to be acknowledged
+    if buf[4] & 0b0100_0000 != 0 {
+        send(server, &[ACCEPTED], server.sessions[index].address);
+        return;
+    }
+
+    let mut x = i16::from_le_bytes(buf[0..2].try_into().unwrap());
+    let mut y = i16::from_le_bytes(buf[2..4].try_into().unwrap());
+    let button_states = buf[4].to_bools();
+    let button_flags = buf[4] & 0b0000_0111;
+    let keys = parse_keys(buf);
+    let scroll_distance = button_states[3] as i32 - button_states[4] as i32;
+
+    let session = &mut server.sessions[index];
+    session.last_seen = Instant::now();
+
+    // Disconnect messages are never dropped, so the client's keys are released straight away
+    if !button_states[5] {
+        let key_changes = keys
+            .iter()
+            .filter(|key| !session.keys.contains(key))
+            .count()
+            + session
+                .keys
+                .iter()
+                .filter(|key| !keys.contains(key))
+                .count();
+        let events = key_changes as u32
+            + (button_flags ^ session.button_flags).count_ones()
+            + (scroll_distance != 0) as u32
+            + (x != 0 || y != 0) as u32;
+
+        let limit = if !session.packets.take(1.0) {
+            Some(Limit::PacketRate)
+        } else if !session.events.take(events as f64) {
+            Some(Limit::EventRate)
+        } else {
+            None
+        };
+
+        if let Some(limit) = limit {
+            if session.dropped == 0 {
+                let ip = canonical_ip(session.address.ip());
+                warn!(
+                    event = "rate_limited", peer = ip, session = session.id, limit = limit.name();
+                    "{} is over the {} limit, dropping packets", ip, limit.name().replace('_', " ")
+                );
             }
+            session.dropped += 1;
+            server.metrics.dropped(limit);
+
+            // Keys and buttons are sent in full in every packet, but movement is relative and would be lost
+            let (dropped_x, dropped_y) = session.dropped_movement;
+            session.dropped_movement = (dropped_x.saturating_add(x), dropped_y.saturating_add(y));
+            return;
+        }
+
+        if session.dropped != 0 {
+            let ip = canonical_ip(session.address.ip());
+            warn!(
+                event = "rate_limit_recovered", peer = ip, session = session.id, dropped = session.dropped;
+                "{} is back within its limits after {} packets were dropped", ip, session.dropped
+            );
+            session.dropped = 0;
+        }
+
+        let (dropped_x, dropped_y) = session.dropped_movement;
+        x = x.saturating_add(dropped_x);
+        y = y.saturating_add(dropped_y);
+        session.dropped_movement = (0, 0);
+    }
+
+    let active = x != 0
+        || y != 0
+        || scroll_distance != 0
+        || button_flags != session.button_flags
+        || keys != session.keys;
+
+    session.button_flags = button_flags;
+    session.keys = keys;
+    if active {
+        session.last_input = Instant::now();
+    }
+
+    let id = session.id;
+
+    if button_states[5] {
+        let ip = canonical_ip(session.address.ip());
+        info!(event = "disconnect", peer = ip, session = id; "connection to {} closed", ip);
+        close_session(server, index, "closed");
+        return;
+    }
+
+    if let Policy::LastActive(handover) = server.settings.policy {
+        let controller_idle = server
+            .sessions
+            .iter()
+            .find(|s| Some(s.id) == server.controller)
+            .is_none_or(|s| s.last_input.elapsed() >= handover);
+
+        if server.controller != Some(id) && active && controller_idle {
+            server.controller = Some(id);
         }
+    }
 
+    update_held(server);
+
+    if has_control(server, id) && !server.paused {
         // Scroll if the user is scrolling
-        let scroll_distance = button_states[3] as i32 - button_states[4] as i32;
         if scroll_distance != 0 {
             server.output_manager.mouse_scroll_y(scroll_distance);
+            server.metrics.injected(Event::Scroll);
+        }
+
+        let session = &mut server.sessions[index];
+        let (x, y) = server
+            .settings
+            .acceleration
+            .apply(x, y, &mut session.remainder);
+        move_pointer(server, x, y);
+    }
+}
+
+/// Move the pointer by `x` and `y`. If it is confined to a region, the server tracks where the pointer is
+/// and moves it there directly, so it can never leave it. Tracking starts from where the pointer is,
+/// or from the middle of the region if the backend can't tell.
+fn move_pointer(server: &mut Server, x: i32, y: i32) {
+    match server.settings.confine {
+        Some(region) => {
+            let (from_x, from_y) = server
+                .pointer
+                .or_else(|| server.output_manager.mouse_location())
+                .unwrap_or_else(|| region.center());
+            let to = region.clamp(from_x.saturating_add(x), from_y.saturating_add(y));
+
+            if server.pointer != Some(to) {
+                server.output_manager.mouse_move_to(to.0, to.1);
+                server.metrics.injected(Event::Move);
+                server.pointer = Some(to);
+            }
+        }
+        None if x != 0 || y != 0 => {
+            server.output_manager.mouse_move_relative(x, y);
+            server.metrics.injected(Event::Move);
+        }
+        None => (),
+    }
+}
+
+/// Get the keys held in an input packet, from the held key list if there is one or the key flags otherwise.
+fn parse_keys(buf: &[u8]) -> Vec<Key> {
+    match buf.get(13) {
+        Some(count) if buf.len() == 14 + *count as usize => buf[14..]
+            .iter()
+            .filter_map(|usage| Key::from_usage(*usage))
+            .collect(),
+        _ => {
+            let key_flags = u64::from_le_bytes(buf[5..13].try_into().unwrap());
+            flags_to_pressed_keys(&key_flags.to_bools())
+        }
+    }
+}
+
+/// Close any sessions which have stopped sending packets.
+fn expire_sessions(server: &mut Server) {
+    while let Some(index) = server
+        .sessions
+        .iter()
+        .position(|s| s.last_seen.elapsed() > SESSION_TIMEOUT)
+    {
+        let session = &server.sessions[index];
+        let ip = canonical_ip(session.address.ip());
+        info!(event = "timeout", peer = ip, session = session.id; "connection to {} timed out", ip);
+        close_session(server, index, "timeout");
+    }
+}
+
+/// Remove the session at `index`, releasing anything it was holding, because of `reason`.
+fn close_session(server: &mut Server, index: usize, reason: &str) {
+    let session = server.sessions.remove(index);
+    server.settings.hooks.disconnected(&session, reason);
+    server.metrics.set_active_sessions(server.sessions.len());
+
+    if server.controller == Some(session.id) {
+        server.controller = None;
+    }
+
+    update_held(server);
+}
+
+/// Check whether the session with the given ID currently has control under the server's policy.
+fn has_control(server: &Server, id: u32) -> bool {
+    match server.settings.policy {
+        Policy::Exclusive | Policy::Merged => true,
+        Policy::LastActive(_) => server.controller == Some(id),
+    }
+}
+
+/// Press and release keys and buttons so that exactly those held by the sessions in control are held down,
+/// or release everything if the server is paused.
+fn update_held(server: &mut Server) {
+    if server.paused {
+        return release_all(server);
+    }
+
+    let mut button_flags = 0;
+    let mut keys: Vec<Key> = Vec::new();
+
+    for session in server.sessions.iter().filter(|s| has_control(server, s.id)) {
+        button_flags |= session.button_flags;
+        for key in &session.keys {
+            if !keys.contains(key) {
+                keys.push(*key);
+            }
+        }
+    }
+
+    set_held(server, button_flags, &keys);
+}
+
+/// Press and release keys and buttons so that exactly those given are held down,
+/// after translating and remapping the keys.
+fn set_held(server: &mut Server, button_flags: u8, keys: &[Key]) {
+    let button_types: Vec<MouseButton> =
+        vec![MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+
+    // Check the button flags and update the mouse state accordingly
+    let button_states = button_flags.to_bools();
+    let previous_button_states = server.held_button_flags.to_bools();
+    for button_id in 0..button_types.len() {
+        if button_states[button_id] ^ previous_button_states[button_id] {
+            match button_states[button_id] {
+                true => {
+                    server.output_manager.mouse_down(button_types[button_id]);
+                    server.metrics.injected(Event::ButtonDown);
+                }
+                false => {
+                    server.output_manager.mouse_up(button_types[button_id]);
+                    server.metrics.injected(Event::ButtonUp);
+                }
+            }
+        }
+    }
+
+    // Update the keys held accordingly, releasing chords in reverse order
+    let keys = match server.settings.translation {
+        Some(translation) if !server.output_manager.types_characters() => translation.apply(keys),
+        _ => keys.to_vec(),
+    };
+    let keys_held = server.settings.remap.apply(&keys);
+    for key in server.held_keys.iter().rev() {
+        if !keys_held.contains(key) {
+            server.output_manager.key_up(*key);
+            server.metrics.injected(Event::KeyUp);
+        }
+    }
+    for key in &keys_held {
+        if !server.held_keys.contains(key) {
+            server.output_manager.key_down(*key);
+            server.metrics.injected(Event::KeyDown);
+        }
+    }
+
+    server.held_button_flags = button_flags;
+    server.metrics.set_held_keys(keys_held.len());
+    server.held_keys = keys_held;
+}
+
+/// Replace the server's settings without closing any sessions. The new access list only applies
+/// to new connections, and held keys are pressed again under the new remapping and translation.
+pub fn reload(server: &mut Server, settings: Settings) {
+    server.settings = settings;
+    server.pointer = None;
+    if let Some(translation) = server.settings.translation {
+        server.output_manager.set_layout(translation.client);
+    }
+    for session in &mut server.sessions {
+        session.packets = TokenBucket::new(server.settings.limits.packet_rate);
+        session.events = TokenBucket::new(server.settings.limits.event_rate);
+    }
+    update_held(server);
+}
+
+/// Carry out a command from `remouse ctl`, returning the response to send back.
+fn control(server: &mut Server, command: Command) -> String {
+    info!(event = "control"; "received control command {:?}", command);
+
+    match command {
+        Command::Sessions => {
+            let mut response = String::new();
+            for session in &server.sessions {
+                response += &format!(
+                    "{}\t{}\tidle {:.1}s{}\n",
+                    session.id,
+                    canonical_ip(session.address.ip()),
+                    session.last_input.elapsed().as_secs_f64(),
+                    match has_control(server, session.id) {
+                        true => "\tin control",
+                        false => "",
+                    }
+                );
+            }
+            match response.is_empty() {
+                true => String::from("no clients connected\n"),
+                false => response,
+            }
         }
+        Command::Kick(id) => match server.sessions.iter().position(|s| s.id == id) {
+            Some(index) => {
+                let ip = canonical_ip(server.sessions[index].address.ip());
+                info!(event = "kick", peer = ip, session = id; "kicked {} (session {})", ip, id);
 
-        // Check the key flags and update the keys held accordingly
-        let key_states = key_flags.to_bools();
-        let previous_key_states = previous_key_flags.to_bools();
-        let keys_held = flags_to_pressed_keys(&key_states);
-        let previous_keys_held = flags_to_pressed_keys(&previous_key_states);
-        for key in &previous_keys_held {
-            if !keys_held.contains(key) {
-                server.output_manager.key_up(*key);
+                let address = server.sessions[index].address;
+                send(server, &[REJECTED], address);
+                server
+                    .kicked
+                    .insert(address, Instant::now() + KICK_TOMBSTONE);
+                close_session(server, index, "kicked");
+                format!("kicked session {}\n", id)
             }
+            None => format!("error: no session {}\n", id),
+        },
+        Command::Pause => {
+            server.paused = true;
+            update_held(server);
+            String::from("paused\n")
+        }
+        Command::Resume => {
+            server.paused = false;
+            update_held(server);
+            String::from("resumed\n")
         }
-        for key in &keys_held {
-            if !previous_keys_held.contains(key) {
-                server.output_manager.key_down(*key);
+        Command::Release => {
+            // Forget what the clients were holding too, so nothing is pressed again until they send it
+            for session in &mut server.sessions {
+                session.button_flags = 0;
+                session.keys.clear();
             }
+            release_all(server);
+            String::from("released all keys and buttons\n")
+        }
+        Command::UnlockPairing => {
+            server.wrong_pins = 0;
+            String::from("pairing unlocked\n")
         }
+        Command::Held => {
+            let button_names = ["Left", "Right", "Middle"];
+            let held: Vec<String> = (0..button_names.len())
+                .filter(|button| server.held_button_flags & (1 << button) != 0)
+                .map(|button| format!("{}Button", button_names[button]))
+                .chain(server.held_keys.iter().map(|key| format!("{:?}", key)))
+                .collect();
 
-        if button_states[5] == true {
-            println!("connection to {} closed", addr.ip());
-            acknowledged_connection = false;
+            match held.is_empty() {
+                true => String::from("nothing held\n"),
+                false => held.join(" ") + "\n",
+            }
         }
+    }
+}
+
+/// Release every key and button currently held down on this machine.
+pub fn release_all(server: &mut Server) {
+    set_held(server, 0, &[]);
+}
 
-        server
-            .output_manager
-            .mouse_move_relative(x as i32, y as i32);
+impl Drop for Server {
+    /// Release everything when the server stops, including when it panics,
+    /// so no keys are left held down after the process exits. Each session's disconnect hook is run too.
+    fn drop(&mut self) {
+        for session in &self.sessions {
+            self.settings.hooks.disconnected(session, "shutdown");
+        }
+
+        if self.held_button_flags != 0 || !self.held_keys.is_empty() {
+            info!(event = "release"; "releasing held keys and buttons");
+
+            // If the backend is what panicked it may panic again, which would abort the process while unwinding
+            if panic::catch_unwind(AssertUnwindSafe(|| release_all(self))).is_err() {
+                error!(event = "release_error"; "failed to release held keys and buttons");
+            }
+        }
     }
 }
 
@@ -149,3 +876,403 @@ impl Flags for u64 {
         return out;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::{fs, path::PathBuf, process};
+
+    const ID: ClientId = ClientId([7; 16]);
+
+    /// A dry run server which requires pairing, with its trust store in a fresh temporary file.
+    fn server(name: &str) -> (Server, PathBuf) {
+        let path = std::env::temp_dir().join(format!("remouse-test-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+
+        let settings = Settings {
+            access: AccessList::new(&[], &[], &Config::empty()).unwrap(),
+            policy: Policy::Merged,
+            acceleration: Acceleration::parse(None, None, None, None).unwrap(),
+            confine: None,
+            remap: Remap::identity(),
+            limits: Limits::parse(None, None, Some("0")).unwrap(),
+            hooks: Hooks::default(),
+            translation: None,
+        };
+        let listeners = Listeners {
+            control: None,
+            discovery: None,
+            mdns: None,
+        };
+        let server = init(
+            Backend::DryRun,
+            "127.0.0.1:0".parse().unwrap(),
+            settings,
+            Some(TrustStore::load(path.clone()).unwrap()),
+            Arc::new(Metrics::default()),
+            listeners,
+        )
+        .unwrap();
+
+        (server, path)
+    }
+
+    /// A socket for a client, which gives up waiting for a response quickly.
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        socket
+    }
+
+    /// Handle `packet` as if it was sent by `client`, returning the server's response.
+    fn exchange(server: &mut Server, client: &UdpSocket, packet: &[u8]) -> Option<u8> {
+        handshake(server, client.local_addr().unwrap(), packet);
+
+        let mut buf = [0; 1];
+        client.recv(&mut buf).ok().map(|_| buf[0])
+    }
+
+    fn connect(id: ClientId) -> Vec<u8> {
+        [&CONNECT_MESSAGE[..], &id.0].concat()
+    }
+
+    fn pair(id: ClientId, pin: u32) -> Vec<u8> {
+        [&PAIR_MESSAGE[..], &id.0, &pin.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn parses_handshakes() {
+        assert!(matches!(
+            parse_handshake(&CONNECT_MESSAGE),
+            Some(Handshake::Connect(None))
+        ));
+        assert!(matches!(
+            parse_handshake(&connect(ID)),
+            Some(Handshake::Connect(Some(ID)))
+        ));
+        assert!(matches!(
+            parse_handshake(&pair(ID, 123456)),
+            Some(Handshake::Pair(ID, 123456))
+        ));
+
+        assert!(parse_handshake(&[]).is_none());
+        assert!(parse_handshake(&CONNECT_MESSAGE[..12]).is_none());
+        assert!(parse_handshake(&connect(ID)[..20]).is_none());
+        assert!(parse_handshake(&PAIR_MESSAGE).is_none());
+        assert!(parse_handshake(&pair(ID, 1)[..32]).is_none());
+        assert!(parse_handshake(&[0; 13]).is_none());
+    }
+
+    #[test]
+    fn pairs_with_the_right_pin_and_remembers_the_client() {
+        let (mut server, path) = server("pair");
+        let client = socket();
+
+        assert_eq!(
+            exchange(&mut server, &client, &connect(ID)),
+            Some(PIN_REQUIRED)
+        );
+        let pin = server.pending_pairings[0].pin;
+
+        assert_eq!(
+            exchange(&mut server, &client, &pair(ID, pin)),
+            Some(ACCEPTED)
+        );
+        assert_eq!(server.sessions.len(), 1);
+        assert!(server.pending_pairings.is_empty());
+        assert!(TrustStore::load(path.clone()).unwrap().contains(&ID));
+
+        // A trusted client connects straight away from anywhere
+        let other = socket();
+        assert_eq!(exchange(&mut server, &other, &connect(ID)), Some(ACCEPTED));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn each_pin_can_only_be_tried_once() {
+        let (mut server, path) = server("retry");
+        let client = socket();
+
+        exchange(&mut server, &client, &connect(ID));
+        let pin = server.pending_pairings[0].pin;
+
+        assert_eq!(
+            exchange(&mut server, &client, &pair(ID, pin + 1)),
+            Some(REJECTED)
+        );
+        assert_eq!(
+            exchange(&mut server, &client, &pair(ID, pin)),
+            Some(REJECTED)
+        );
+        assert!(server.sessions.is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pins_only_pair_the_client_they_were_shown_to() {
+        let (mut server, _) = server("other_client");
+        let client = socket();
+        let other = socket();
+
+        exchange(&mut server, &client, &connect(ID));
+        let pin = server.pending_pairings[0].pin;
+
+        assert_eq!(
+            exchange(&mut server, &other, &pair(ID, pin)),
+            Some(REJECTED)
+        );
+        assert_eq!(
+            exchange(&mut server, &client, &pair(ClientId([8; 16]), pin)),
+            Some(REJECTED)
+        );
+        assert!(server.sessions.is_empty());
+    }
+
+    #[test]
+    fn expired_pins_are_rejected() {
+        let (mut server, _) = server("expired");
+        let client = socket();
+
+        exchange(&mut server, &client, &connect(ID));
+        let pending = &mut server.pending_pairings[0];
+        pending.started -= PAIRING_TIMEOUT;
+        let pin = pending.pin;
+
+        assert_eq!(
+            exchange(&mut server, &client, &pair(ID, pin)),
+            Some(REJECTED)
+        );
+        assert!(server.sessions.is_empty());
+    }
+
+    #[test]
+    fn pending_pairings_are_capped() {
+        let (mut server, _) = server("pending_cap");
+        let client = socket();
+
+        for port in 0..MAX_PENDING_PAIRINGS as u16 {
+            server.pending_pairings.push(PendingPairing {
+                address: SocketAddr::from(([127, 0, 0, 1], port)),
+                id: ID,
+                pin: 0,
+                started: Instant::now(),
+            });
+        }
+        assert_eq!(exchange(&mut server, &client, &connect(ID)), Some(REJECTED));
+        assert_eq!(server.pending_pairings.len(), MAX_PENDING_PAIRINGS);
+
+        // Expired pairings make room for new ones
+        server.pending_pairings[0].started -= PAIRING_TIMEOUT;
+        assert_eq!(
+            exchange(&mut server, &client, &connect(ID)),
+            Some(PIN_REQUIRED)
+        );
+        assert_eq!(server.pending_pairings.len(), MAX_PENDING_PAIRINGS);
+        assert_eq!(
+            server.pending_pairings.last().unwrap().address,
+            client.local_addr().unwrap()
+        );
+    }
+
+    #[test]
+    fn too_many_wrong_pins_lock_pairing() {
+        let (mut server, _) = server("lock");
+        let client = socket();
+
+        for _ in 0..MAX_WRONG_PINS {
+            exchange(&mut server, &client, &connect(ID));
+            let pin = server.pending_pairings[0].pin;
+            exchange(&mut server, &client, &pair(ID, pin ^ 1));
+        }
+
+        assert_eq!(exchange(&mut server, &client, &connect(ID)), Some(REJECTED));
+        assert!(server.pending_pairings.is_empty());
+
+        control(&mut server, Command::UnlockPairing);
+        assert_eq!(
+            exchange(&mut server, &client, &connect(ID)),
+            Some(PIN_REQUIRED)
+        );
+    }
+
+    #[test]
+    fn clients_without_an_id_cannot_pair() {
+        let (mut server, _) = server("no_id");
+        let client = socket();
+
+        assert_eq!(
+            exchange(&mut server, &client, &CONNECT_MESSAGE),
+            Some(REJECTED)
+        );
+        assert!(server.pending_pairings.is_empty());
+    }
+
+    #[test]
+    fn denied_addresses_are_rejected_before_pairing() {
+        let (mut server, _) = server("denied");
+        let client = socket();
+
+        server.settings.access = AccessList::new(&["10.0.0.0/8"], &[], &Config::empty()).unwrap();
+        assert_eq!(exchange(&mut server, &client, &connect(ID)), Some(REJECTED));
+
+        server.settings.access = AccessList::new(&[], &["127.0.0.0/8"], &Config::empty()).unwrap();
+        assert_eq!(exchange(&mut server, &client, &connect(ID)), Some(REJECTED));
+        assert!(server.pending_pairings.is_empty());
+    }
+
+    #[test]
+    fn handshakes_are_ignored_during_a_cooldown() {
+        let (mut server, _) = server("cooldown");
+        let client = socket();
+        server.settings.limits.handshake_cooldown = Duration::from_secs(60);
+
+        assert_eq!(exchange(&mut server, &client, &[0; 13]), Some(REJECTED));
+        assert_eq!(exchange(&mut server, &client, &connect(ID)), None);
+
+        server.cooldowns.clear();
+        assert_eq!(
+            exchange(&mut server, &client, &connect(ID)),
+            Some(PIN_REQUIRED)
+        );
+    }
+
+    #[test]
+    fn cooldowns_are_capped() {
+        let (mut server, _) = server("cooldown_cap");
+        server.settings.limits.handshake_cooldown = Duration::from_secs(60);
+        let ip = |i: usize| IpAddr::from((i as u32).to_be_bytes());
+
+        for i in 0..MAX_COOLDOWNS {
+            start_cooldown(&mut server, ip(i));
+        }
+        start_cooldown(&mut server, ip(MAX_COOLDOWNS));
+        assert_eq!(server.cooldowns.len(), MAX_COOLDOWNS);
+        assert!(!server.cooldowns.contains_key(&ip(MAX_COOLDOWNS)));
+
+        // Expired cooldowns make room for new ones
+        *server.cooldowns.get_mut(&ip(0)).unwrap() = Instant::now();
+        start_cooldown(&mut server, ip(MAX_COOLDOWNS));
+        assert!(!server.cooldowns.contains_key(&ip(0)));
+        assert!(server.cooldowns.contains_key(&ip(MAX_COOLDOWNS)));
+    }
+
+    /// Connect a client without pairing, returning its socket.
+    fn join(server: &mut Server) -> UdpSocket {
+        server.trust_store = None;
+        let client = socket();
+        assert_eq!(exchange(server, &client, &CONNECT_MESSAGE), Some(ACCEPTED));
+        client
+    }
+
+    /// Handle an input packet from `client` with the given movement, button flags and held keys.
+    fn input(server: &mut Server, client: &UdpSocket, x: i16, flags: u8, keys: &[Key]) {
+        let mut packet = [x.to_le_bytes(), [0, 0]].concat();
+        packet.push(flags);
+        packet.extend_from_slice(&[0; 8]);
+        packet.push(keys.len() as u8);
+        packet.extend(keys.iter().map(|key| key.usage()));
+
+        let addr = client.local_addr().unwrap();
+        let index = server
+            .sessions
+            .iter()
+            .position(|s| s.address == addr)
+            .unwrap();
+        handle_input(server, index, &packet);
+    }
+
+    #[test]
+    fn last_active_hands_over_after_the_delay_and_releases_the_previous_keys() {
+        let (mut server, _) = server("last_active");
+        let handover = Duration::from_secs(60);
+        server.settings.policy = Policy::LastActive(handover);
+        let first = join(&mut server);
+        let second = join(&mut server);
+        let (first_id, second_id) = (server.sessions[0].id, server.sessions[1].id);
+
+        input(&mut server, &first, 0, 0b001, &[Key::A]);
+        assert_eq!(server.controller, Some(first_id));
+
+        // The first client was active too recently to lose control
+        input(&mut server, &second, 5, 0, &[Key::B]);
+        assert_eq!(server.controller, Some(first_id));
+        assert_eq!(server.held_keys, vec![Key::A]);
+        assert_eq!(server.held_button_flags, 0b001);
+
+        server.sessions[0].last_input -= handover;
+        input(&mut server, &second, 5, 0, &[Key::B]);
+        assert_eq!(server.controller, Some(second_id));
+        assert_eq!(server.held_keys, vec![Key::B]);
+        assert_eq!(server.held_button_flags, 0);
+
+        // Holding the same keys isn't activity, so the first client can't take control back by repeating them
+        server.sessions[1].last_input -= handover;
+        input(&mut server, &first, 0, 0b001, &[Key::A]);
+        assert_eq!(server.controller, Some(second_id));
+    }
+
+    #[test]
+    fn merged_sessions_hold_keys_together_and_release_their_own() {
+        let (mut server, _) = server("merged");
+        let first = join(&mut server);
+        let second = join(&mut server);
+
+        input(&mut server, &first, 0, 0b001, &[Key::LeftShift, Key::A]);
+        input(&mut server, &second, 0, 0b010, &[Key::A, Key::B]);
+        assert_eq!(server.held_keys, vec![Key::LeftShift, Key::A, Key::B]);
+        assert_eq!(server.held_button_flags, 0b011);
+
+        input(&mut server, &second, 0, 0b0010_0000, &[]);
+        assert_eq!(server.sessions.len(), 1);
+        assert_eq!(server.held_keys, vec![Key::LeftShift, Key::A]);
+        assert_eq!(server.held_button_flags, 0b001);
+
+        input(&mut server, &first, 0, 0b0010_0000, &[]);
+        assert!(server.held_keys.is_empty());
+        assert_eq!(server.held_button_flags, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn disconnect_hooks_are_told_why_the_session_ended() {
+        let (mut server, _) = server("hooks");
+        let log = std::env::temp_dir().join(format!("remouse-test-{}-reasons", process::id()));
+        let _ = fs::remove_file(&log);
+        server.settings.hooks.on_disconnect = Some(format!(
+            "echo $REMOUSE_SESSION $REMOUSE_REASON >> {}",
+            log.display()
+        ));
+        let clients: Vec<_> = (0..4).map(|_| join(&mut server)).collect();
+        let ids: Vec<_> = server.sessions.iter().map(|s| s.id).collect();
+
+        input(&mut server, &clients[0], 0, 0b0010_0000, &[]);
+        server.sessions[0].last_seen = Instant::now() - SESSION_TIMEOUT * 2;
+        expire_sessions(&mut server);
+        control(&mut server, Command::Kick(ids[2]));
+        drop(server);
+
+        let mut expected = vec![
+            format!("{} closed", ids[0]),
+            format!("{} timeout", ids[1]),
+            format!("{} kicked", ids[2]),
+            format!("{} shutdown", ids[3]),
+        ];
+        expected.sort();
+        for _ in 0..200 {
+            let contents = fs::read_to_string(&log).unwrap_or_default();
+            let mut reasons: Vec<_> = contents.lines().map(String::from).collect();
+            if reasons.len() == expected.len() {
+                reasons.sort();
+                assert_eq!(reasons, expected);
+                fs::remove_file(log).unwrap();
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the disconnect hooks didn't all run");
+    }
+}
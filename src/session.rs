@@ -0,0 +1,74 @@
+use crate::keyboard::Key;
+use crate::ratelimit::{Limits, TokenBucket};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How long a session can go without sending any packets before it is closed.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of a single connected client.
+pub struct Session {
+    pub id: u32,
+    pub address: SocketAddr,
+    /// The mouse button flags last sent by the client, excluding scrolling and connection info.
+    pub button_flags: u8,
+    /// The keys last sent by the client.
+    pub keys: Vec<Key>,
+    /// When the client last sent any packet.
+    pub last_seen: Instant,
+    /// When the client last moved the mouse, scrolled, or changed which keys or buttons are held.
+    pub last_input: Instant,
+    /// The fractional mouse movement left over after acceleration, carried over to the next packet.
+    pub remainder: (f64, f64),
+    /// The packets the client may still send before it is rate limited.
+    pub packets: TokenBucket,
+    /// The input events the client may still cause before it is rate limited.
+    pub events: TokenBucket,
+    /// How many packets have been dropped since the client was last within its limits.
+    pub dropped: u32,
+    /// The mouse movement in dropped packets, added to the next packet which isn't dropped.
+    pub dropped_movement: (i16, i16),
+}
+
+impl Session {
+    pub fn new(id: u32, address: SocketAddr, limits: &Limits) -> Session {
+        Session {
+            id,
+            address,
+            button_flags: 0,
+            keys: Vec::new(),
+            last_seen: Instant::now(),
+            last_input: Instant::now(),
+            remainder: (0.0, 0.0),
+            packets: TokenBucket::new(limits.packet_rate),
+            events: TokenBucket::new(limits.event_rate),
+            dropped: 0,
+            dropped_movement: (0, 0),
+        }
+    }
+}
+
+/// How input from several connected clients is combined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    /// The first client to connect has control, and others are refused until it disconnects.
+    Exclusive,
+    /// The most recently active client has control, once the previous one has been idle for the handover delay.
+    LastActive(Duration),
+    /// Every client has control at once, with the keys and buttons held by each one tracked separately.
+    Merged,
+}
+
+impl Policy {
+    /// Parse a policy from its name, using `handover` as the delay for `last-active`.
+    pub fn from_name(name: &str, handover: Duration) -> Option<Policy> {
+        match name {
+            "exclusive" => Some(Policy::Exclusive),
+            "last-active" => Some(Policy::LastActive(handover)),
+            "merged" => Some(Policy::Merged),
+            _ => None,
+        }
+    }
+}
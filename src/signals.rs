@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The signal received since the last call to `take`, stored as its `Signal` discriminant plus one.
+static PENDING: AtomicU8 = AtomicU8::new(0);
+
+/// A request from the OS or the user for the server to stop, or to reload its settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    /// SIGINT, or Ctrl+C on Windows.
+    Interrupt,
+    /// SIGTERM, or the console window being closed or the user logging off on Windows.
+    Terminate,
+    /// SIGHUP, which asks the server to reload its settings.
+    Hangup,
+}
+
+const SIGNALS: [Signal; 3] = [Signal::Interrupt, Signal::Terminate, Signal::Hangup];
+
+/// Take the most recently received signal, if one has arrived since the last call.
+pub fn take() -> Option<Signal> {
+    match PENDING.swap(0, Ordering::SeqCst) {
+        0 => None,
+        n => Some(SIGNALS[n as usize - 1]),
+    }
+}
+
+fn store(signal: Signal) {
+    PENDING.store(signal as u8 + 1, Ordering::SeqCst);
+}
+
+/// Start catching SIGINT, SIGTERM and SIGHUP so they can be handled by `take`.
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn handler(signal: libc::c_int) {
+        match signal {
+            libc::SIGINT => store(Signal::Interrupt),
+            libc::SIGTERM => store(Signal::Terminate),
+            libc::SIGHUP => store(Signal::Hangup),
+            _ => (),
+        }
+    }
+
+    for signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            libc::signal(
+                *signal,
+                handler as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+/// Start catching console control events so they can be handled by `take`.
+#[cfg(windows)]
+pub fn install() {
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::um::{consoleapi::SetConsoleCtrlHandler, wincon};
+
+    unsafe extern "system" fn handler(event: DWORD) -> BOOL {
+        let signal = match event {
+            wincon::CTRL_C_EVENT | wincon::CTRL_BREAK_EVENT => Signal::Interrupt,
+            wincon::CTRL_CLOSE_EVENT | wincon::CTRL_LOGOFF_EVENT | wincon::CTRL_SHUTDOWN_EVENT => {
+                Signal::Terminate
+            }
+            _ => return FALSE,
+        };
+        store(signal);
+
+        // Windows ends the process as soon as this returns for anything but Ctrl+C,
+        // so give the server a moment to release any held keys first
+        if signal != Signal::Interrupt {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+
+        TRUE
+    }
+
+    unsafe {
+        SetConsoleCtrlHandler(Some(handler), TRUE);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::keyboard::{all_keys, Key};
+use crate::output::Output;
+use crate::pointer::Region;
+use enigo::MouseButton;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, Write},
+    mem::size_of,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    slice,
+    thread::sleep,
+    time::Duration,
+};
+
+const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0x00;
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// The name of the virtual device, which the client's evdev capture skips so it doesn't capture its own output.
+pub const DEVICE_NAME: &str = "remouse virtual input";
+
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+const UI_DEV_SETUP: libc::c_ulong = 0x405c_5503;
+const UI_SET_EVBIT: libc::c_ulong = 0x4004_5564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x4004_5565;
+const UI_SET_RELBIT: libc::c_ulong = 0x4004_5566;
+const UI_SET_ABSBIT: libc::c_ulong = 0x4004_5567;
+const UI_ABS_SETUP: libc::c_ulong = 0x401c_5504;
+
+/// Mirror of the kernel's `struct uinput_setup`.
+#[repr(C)]
+struct UinputSetup {
+    id: libc::input_id,
+    name: [u8; 80],
+    ff_effects_max: u32,
+}
+
+/// Mirror of the kernel's `struct uinput_abs_setup`.
+#[repr(C)]
+struct UinputAbsSetup {
+    code: u16,
+    absinfo: libc::input_absinfo,
+}
+
+/// A virtual keyboard and mouse created through `/dev/uinput`.
+/// Events are injected by the kernel, so this works under Wayland, X11 and on bare consoles alike.
+pub struct Device {
+    file: File,
+    /// A second device which moves the pointer to absolute positions, created the first time it is needed.
+    /// It is separate so the compositor still treats the main device as a mouse.
+    absolute: Option<std::io::Result<File>>,
+}
+
+impl Device {
+    /// Create the virtual device, registering every key and button the server can press.
+    pub fn new() -> std::io::Result<Device> {
+        let file = create(|fd| {
+            ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_ulong)?;
+            ioctl(fd, UI_SET_EVBIT, EV_REL as libc::c_ulong)?;
+            ioctl(fd, UI_SET_EVBIT, EV_SYN as libc::c_ulong)?;
+            for code in all_keys().map(key_code) {
+                ioctl(fd, UI_SET_KEYBIT, code as libc::c_ulong)?;
+            }
+            for code in &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE] {
+                ioctl(fd, UI_SET_KEYBIT, *code as libc::c_ulong)?;
+            }
+            for code in &[REL_X, REL_Y, REL_WHEEL] {
+                ioctl(fd, UI_SET_RELBIT, *code as libc::c_ulong)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(Device {
+            file,
+            absolute: None,
+        })
+    }
+
+    /// Create the absolute pointer, with an axis range covering every pixel of `desktop`
+    /// so that positions are in pixels, as the compositor maps the range onto the whole desktop.
+    fn new_absolute(desktop: Region) -> std::io::Result<File> {
+        create(|fd| {
+            ioctl(fd, UI_SET_EVBIT, EV_ABS as libc::c_ulong)?;
+            ioctl(fd, UI_SET_EVBIT, EV_SYN as libc::c_ulong)?;
+            // Without a button the device would be taken for a joystick rather than a pointer
+            ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_ulong)?;
+            ioctl(fd, UI_SET_KEYBIT, BTN_LEFT as libc::c_ulong)?;
+
+            for (code, size) in &[(ABS_X, desktop.width), (ABS_Y, desktop.height)] {
+                ioctl(fd, UI_SET_ABSBIT, *code as libc::c_ulong)?;
+
+                let setup = UinputAbsSetup {
+                    code: *code,
+                    absinfo: libc::input_absinfo {
+                        value: 0,
+                        minimum: 0,
+                        maximum: size - 1,
+                        fuzz: 0,
+                        flat: 0,
+                        resolution: 0,
+                    },
+                };
+                ioctl(
+                    fd,
+                    UI_ABS_SETUP,
+                    &setup as *const UinputAbsSetup as libc::c_ulong,
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Write a single event followed by a sync report.
+    fn emit(&mut self, event_type: u16, code: u16, value: i32) {
+        write_event(&mut self.file, event_type, code, value);
+        write_event(&mut self.file, EV_SYN, SYN_REPORT, 0);
+    }
+}
+
+/// Create a uinput device named `DEVICE_NAME`, after `configure` has registered the events it sends.
+fn create(configure: impl FnOnce(libc::c_int) -> std::io::Result<()>) -> std::io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/uinput")?;
+    let fd = file.as_raw_fd();
+
+    configure(fd)?;
+
+    let mut setup = UinputSetup {
+        id: libc::input_id {
+            bustype: BUS_VIRTUAL,
+            vendor: 0,
+            product: 0,
+            version: 1,
+        },
+        name: [0; 80],
+        ff_effects_max: 0,
+    };
+    setup.name[..DEVICE_NAME.len()].copy_from_slice(DEVICE_NAME.as_bytes());
+
+    ioctl(
+        fd,
+        UI_DEV_SETUP,
+        &setup as *const UinputSetup as libc::c_ulong,
+    )?;
+    ioctl(fd, UI_DEV_CREATE, 0)?;
+
+    // Give the compositor or X server a moment to pick up the new device,
+    // otherwise the first few events are dropped.
+    sleep(Duration::from_millis(200));
+
+    Ok(file)
+}
+
+fn write_event(file: &mut File, event_type: u16, code: u16, value: i32) {
+    let event = libc::input_event {
+        time: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        type_: event_type,
+        code,
+        value,
+    };
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            &event as *const libc::input_event as *const u8,
+            size_of::<libc::input_event>(),
+        )
+    };
+
+    if let Err(e) = file.write_all(bytes) {
+        error!(event = "backend_error", error = e; "failed to write uinput event: {}", e);
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        let _ = ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY, 0);
+        if let Some(Ok(absolute)) = &self.absolute {
+            let _ = ioctl(absolute.as_raw_fd(), UI_DEV_DESTROY, 0);
+        }
+    }
+}
+
+impl Output for Device {
+    fn key_down(&mut self, key: Key) {
+        self.emit(EV_KEY, key_code(key), 1);
+    }
+
+    fn key_up(&mut self, key: Key) {
+        self.emit(EV_KEY, key_code(key), 0);
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        if let Some(code) = button_code(button) {
+            self.emit(EV_KEY, code, 1);
+        }
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        if let Some(code) = button_code(button) {
+            self.emit(EV_KEY, code, 0);
+        }
+    }
+
+    fn mouse_scroll_y(&mut self, length: i32) {
+        self.emit(EV_REL, REL_WHEEL, length);
+    }
+
+    fn mouse_move_relative(&mut self, x: i32, y: i32) {
+        if x == 0 && y == 0 {
+            return;
+        }
+
+        write_event(&mut self.file, EV_REL, REL_X, x);
+        write_event(&mut self.file, EV_REL, REL_Y, y);
+        write_event(&mut self.file, EV_SYN, SYN_REPORT, 0);
+    }
+
+    /// Move the pointer with the absolute pointer, which needs xrandr to find the size of the desktop.
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        let absolute = self.absolute.get_or_insert_with(|| {
+            let absolute = Region::desktop()
+                .map_err(Error::other)
+                .and_then(Device::new_absolute);
+
+            if let Err(e) = &absolute {
+                error!(event = "backend_error", error = e; "failed to create uinput absolute pointer, the pointer can't be confined: {}", e);
+            }
+            absolute
+        });
+
+        if let Ok(absolute) = absolute {
+            write_event(absolute, EV_ABS, ABS_X, x);
+            write_event(absolute, EV_ABS, ABS_Y, y);
+            write_event(absolute, EV_SYN, SYN_REPORT, 0);
+        }
+    }
+
+    fn mouse_location(&self) -> Option<(i32, i32)> {
+        crate::output::mouse_location()
+    }
+}
+
+pub fn ioctl(
+    fd: libc::c_int,
+    request: libc::c_ulong,
+    argument: libc::c_ulong,
+) -> std::io::Result<()> {
+    match unsafe { libc::ioctl(fd, request as _, argument) } {
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Convert a mouse button into its Linux input event code.
+fn button_code(button: MouseButton) -> Option<u16> {
+    match button {
+        MouseButton::Left => Some(BTN_LEFT),
+        MouseButton::Right => Some(BTN_RIGHT),
+        MouseButton::Middle => Some(BTN_MIDDLE),
+        _ => None,
+    }
+}
+
+/// Convert a `Key` into its Linux input event code, as defined in `linux/input-event-codes.h`.
+pub fn key_code(key: Key) -> u16 {
+    match key {
+        Key::A => 30,
+        Key::B => 48,
+        Key::C => 46,
+        Key::D => 32,
+        Key::E => 18,
+        Key::F => 33,
+        Key::G => 34,
+        Key::H => 35,
+        Key::I => 23,
+        Key::J => 36,
+        Key::K => 37,
+        Key::L => 38,
+        Key::M => 50,
+        Key::N => 49,
+        Key::O => 24,
+        Key::P => 25,
+        Key::Q => 16,
+        Key::R => 19,
+        Key::S => 31,
+        Key::T => 20,
+        Key::U => 22,
+        Key::V => 47,
+        Key::W => 17,
+        Key::X => 45,
+        Key::Y => 21,
+        Key::Z => 44,
+        Key::Key0 => 11,
+        Key::Key1 => 2,
+        Key::Key2 => 3,
+        Key::Key3 => 4,
+        Key::Key4 => 5,
+        Key::Key5 => 6,
+        Key::Key6 => 7,
+        Key::Key7 => 8,
+        Key::Key8 => 9,
+        Key::Key9 => 10,
+        Key::Space => 57,
+        Key::Enter => 28,
+        Key::LeftShift => 42,
+        Key::LeftCtrl => 29,
+        Key::Down => 108,
+        Key::Left => 105,
+        Key::Right => 106,
+        Key::Up => 103,
+        Key::Apostrophe => 40,
+        Key::Backquote => 41,
+        Key::Backslash => 43,
+        Key::Comma => 51,
+        Key::Equal => 13,
+        Key::LeftBracket => 26,
+        Key::Minus => 12,
+        Key::Period => 52,
+        Key::RightBracket => 27,
+        Key::Semicolon => 39,
+        Key::Slash => 53,
+        Key::Backspace => 14,
+        Key::Delete => 111,
+        Key::End => 107,
+        Key::Home => 102,
+        Key::PageDown => 109,
+        Key::PageUp => 104,
+        Key::Tab => 15,
+        Key::CapsLock => 58,
+        Key::LeftAlt => 56,
+        Key::Escape => 1,
+        Key::F1 => 59,
+        Key::F2 => 60,
+        Key::F3 => 61,
+        Key::F4 => 62,
+        Key::F5 => 63,
+        Key::F6 => 64,
+        Key::F7 => 65,
+        Key::F8 => 66,
+        Key::F9 => 67,
+        Key::F10 => 68,
+        Key::F11 => 87,
+        Key::F12 => 88,
+        Key::RightShift => 54,
+        Key::RightCtrl => 97,
+        Key::RightAlt => 100,
+        Key::LeftMeta => 125,
+        Key::RightMeta => 126,
+        Key::IntlBackslash => 86,
+    }
+}